@@ -0,0 +1,63 @@
+CREATE TABLE IF NOT EXISTS records.session_occurrences (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    form_id UUID NOT NULL REFERENCES records.session_forms(id) ON DELETE CASCADE,
+    seq int NOT NULL,
+    start_time timestamp with time zone NOT NULL,
+    end_time timestamp with time zone NOT NULL,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (form_id, seq)
+);
+
+CREATE INDEX IF NOT EXISTS session_occurrences_start_time_idx ON records.session_occurrences(start_time);
+
+-- the backend refuses series of more than 520 occurrences, so stop here rather than cut one short
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM records.session_forms f
+        WHERE f.recurrence IS NOT NULL AND f.recurrence_end IS NOT NULL
+            AND ((f.start_time AT TIME ZONE 'Europe/London') + 520 * f.recurrence)
+                AT TIME ZONE 'Europe/London' <= f.recurrence_end
+    ) THEN
+        RAISE EXCEPTION 'a session series has more than 520 occurrences, shorten its recurrence_end first';
+    END IF;
+END
+$$;
+
+-- expand the existing forms the way the backend does: whole steps from the series start in
+-- UK local time, up to recurrence_end
+INSERT INTO records.session_occurrences(form_id, seq, start_time, end_time)
+SELECT f.id, s.n, s.start_time,
+    ((f.end_time AT TIME ZONE 'Europe/London') + s.n * COALESCE(f.recurrence, interval '0'))
+        AT TIME ZONE 'Europe/London'
+FROM records.session_forms f
+CROSS JOIN LATERAL (
+    SELECT n, ((f.start_time AT TIME ZONE 'Europe/London') + n * COALESCE(f.recurrence, interval '0'))
+        AT TIME ZONE 'Europe/London' AS start_time
+    FROM generate_series(
+        0, CASE WHEN f.recurrence IS NULL OR f.recurrence_end IS NULL THEN 0 ELSE 519 END
+    ) AS n
+) s
+WHERE s.n = 0 OR s.start_time <= f.recurrence_end;
+
+-- bookings are now made against a single occurrence rather than the whole series. Existing
+-- ones move to the occurrence that was next when they were made, or the last one.
+ALTER TABLE records.bookings
+ADD occurrence_id UUID REFERENCES records.session_occurrences(id) ON DELETE CASCADE;
+UPDATE records.bookings b
+SET occurrence_id = COALESCE(
+    (
+        SELECT o.id FROM records.session_occurrences o
+        WHERE o.form_id = b.form_id AND o.end_time >= b.created_at
+        ORDER BY o.seq LIMIT 1
+    ),
+    (
+        SELECT o.id FROM records.session_occurrences o
+        WHERE o.form_id = b.form_id
+        ORDER BY o.seq DESC LIMIT 1
+    )
+);
+ALTER TABLE records.bookings DROP CONSTRAINT bookings_pkey;
+ALTER TABLE records.bookings DROP COLUMN form_id;
+ALTER TABLE records.bookings ALTER COLUMN occurrence_id SET NOT NULL;
+ALTER TABLE records.bookings ADD PRIMARY KEY (user_id, occurrence_id);
@@ -0,0 +1,32 @@
+CREATE TABLE IF NOT EXISTS records.occurrence_exceptions (
+    occurrence_id UUID PRIMARY KEY REFERENCES records.session_occurrences(id) ON DELETE CASCADE,
+    author_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    cancelled bool NOT NULL DEFAULT false,
+    start_time timestamp with time zone,
+    end_time timestamp with time zone,
+    location text,
+    user_limit smallint,
+    reason text,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT check_times_filled CHECK (
+        (start_time IS NOT NULL AND end_time IS NOT NULL) OR
+            (start_time IS NULL AND end_time IS NULL)
+        )
+);
+
+-- occurrences with any per-date exception applied over the series values
+CREATE OR REPLACE VIEW records.effective_occurrences AS
+SELECT
+    o.id,
+    o.form_id,
+    o.seq,
+    COALESCE(e.start_time, o.start_time) AS start_time,
+    COALESCE(e.end_time, o.end_time) AS end_time,
+    COALESCE(e.location, f.location) AS location,
+    COALESCE(e.user_limit, f.user_limit) AS user_limit,
+    f.tier,
+    COALESCE(e.cancelled, false) AS cancelled,
+    o.created_at
+FROM records.session_occurrences o
+JOIN records.session_forms f ON f.id = o.form_id
+LEFT JOIN records.occurrence_exceptions e ON e.occurrence_id = o.id;
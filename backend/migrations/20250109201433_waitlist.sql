@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS records.waitlist (
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    occurrence_id UUID NOT NULL REFERENCES records.session_occurrences(id) ON DELETE CASCADE,
+    created_at timestamp with time zone NOT NULL DEFAULT clock_timestamp(),
+    PRIMARY KEY (user_id, occurrence_id)
+);
+
+CREATE INDEX IF NOT EXISTS waitlist_occurrence_idx ON records.waitlist(occurrence_id, created_at);
+
+ALTER TABLE records.bookings
+ADD promoted_at timestamp with time zone;
@@ -0,0 +1,5 @@
+-- how long before an occurrence starts each tier may book it, NULL meaning as soon as published
+ALTER TABLE records.session_forms
+ADD team_booking_window interval,
+ADD member_booking_window interval,
+ADD non_member_booking_window interval;
@@ -0,0 +1,29 @@
+ALTER TABLE records.session_forms
+ADD allocation text NOT NULL DEFAULT 'first_come',
+ADD ballot_cutoff interval,
+ADD ballot_weighting text NOT NULL DEFAULT 'none',
+ADD CONSTRAINT check_allocation CHECK (allocation IN ('first_come', 'ballot')),
+ADD CONSTRAINT check_ballot_weighting CHECK (ballot_weighting IN ('none', 'tier', 'losses'));
+
+CREATE TABLE IF NOT EXISTS records.ballot_entries (
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    occurrence_id UUID NOT NULL REFERENCES records.session_occurrences(id) ON DELETE CASCADE,
+    tier smallint NOT NULL REFERENCES auth.tiers(tier) ON DELETE RESTRICT,
+    outcome text,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, occurrence_id),
+    CONSTRAINT check_outcome CHECK (outcome IN ('allocated', 'waitlisted'))
+);
+
+-- everything needed to audit a draw and reproduce it from the seed
+CREATE TABLE IF NOT EXISTS records.ballot_draws (
+    occurrence_id UUID PRIMARY KEY REFERENCES records.session_occurrences(id) ON DELETE CASCADE,
+    seed bigint NOT NULL,
+    weighting text NOT NULL,
+    places int,
+    entrants jsonb NOT NULL,
+    allocated UUID[] NOT NULL,
+    waitlisted UUID[] NOT NULL,
+    drawn_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    drawn_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
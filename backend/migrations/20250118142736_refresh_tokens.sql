@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS auth.refresh_tokens (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    jti UUID NOT NULL UNIQUE,
+    device_label TEXT,
+    user_agent TEXT,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at timestamp with time zone NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_idx ON auth.refresh_tokens(user_id);
+
+ALTER TABLE auth.users
+DROP COLUMN IF EXISTS jti;
@@ -0,0 +1,13 @@
+ALTER TABLE auth.refresh_tokens
+ADD revoked_at timestamp with time zone;
+
+CREATE TABLE IF NOT EXISTS auth.security_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    kind text NOT NULL,
+    refresh_token_id UUID,
+    user_agent text,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS security_events_user_idx ON auth.security_events(user_id, created_at);
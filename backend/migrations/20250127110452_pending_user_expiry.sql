@@ -0,0 +1,2 @@
+ALTER TABLE auth.pending_users
+ADD token_sent_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP;
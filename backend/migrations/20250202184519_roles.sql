@@ -0,0 +1,42 @@
+CREATE TABLE IF NOT EXISTS auth.roles (
+    name text PRIMARY KEY,
+    description text NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS auth.permissions (
+    name text PRIMARY KEY,
+    description text NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS auth.role_permissions (
+    role text NOT NULL REFERENCES auth.roles(name) ON DELETE CASCADE,
+    permission text NOT NULL REFERENCES auth.permissions(name) ON DELETE CASCADE,
+    PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE IF NOT EXISTS auth.user_roles (
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    role text NOT NULL REFERENCES auth.roles(name) ON DELETE CASCADE,
+    granted_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    granted_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, role)
+);
+
+INSERT INTO auth.roles VALUES
+    ('captain', 'Club captain'),
+    ('treasurer', 'Treasurer'),
+    ('social_secretary', 'Social secretary'),
+    ('session_coordinator', 'Session coordinator');
+
+INSERT INTO auth.permissions VALUES
+    ('manage_sessions', 'Create sessions and change any session'),
+    ('view_members', 'See the membership list'),
+    ('view_payments', 'See payment and order data');
+
+INSERT INTO auth.role_permissions VALUES
+    ('captain', 'manage_sessions'),
+    ('captain', 'view_members'),
+    ('treasurer', 'view_members'),
+    ('treasurer', 'view_payments'),
+    ('social_secretary', 'view_members'),
+    ('session_coordinator', 'manage_sessions');
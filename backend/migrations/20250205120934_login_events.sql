@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS auth.login_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES auth.users(id) ON DELETE CASCADE,
+    shortcode text NOT NULL,
+    kind text NOT NULL,
+    ip text,
+    user_agent text,
+    success bool NOT NULL,
+    failure_reason text,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT check_kind CHECK (kind IN ('login', 'refresh'))
+);
+
+CREATE INDEX IF NOT EXISTS login_events_user_idx ON auth.login_events(user_id, created_at);
+CREATE INDEX IF NOT EXISTS login_events_ip_idx ON auth.login_events(ip, created_at);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS auth.login_throttles (
+    scope text NOT NULL,
+    key text NOT NULL,
+    failures integer NOT NULL DEFAULT 0,
+    last_failure_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until timestamp with time zone,
+    PRIMARY KEY (scope, key),
+    CONSTRAINT check_scope CHECK (scope IN ('shortcode', 'ip'))
+);
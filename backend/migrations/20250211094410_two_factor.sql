@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS auth.user_totp (
+    user_id UUID PRIMARY KEY REFERENCES auth.users(id) ON DELETE CASCADE,
+    secret text NOT NULL,
+    enabled_at timestamp with time zone,
+    last_used_step bigint NOT NULL DEFAULT 0,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS auth.recovery_codes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    code_hash text NOT NULL,
+    used_at timestamp with time zone
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_idx ON auth.recovery_codes(user_id);
+
+CREATE TABLE IF NOT EXISTS auth.mfa_challenges (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    keep_login bool NOT NULL,
+    device_label text,
+    attempts integer NOT NULL DEFAULT 0,
+    expires_at timestamp with time zone NOT NULL
+);
+
+ALTER TABLE auth.refresh_tokens ADD COLUMN IF NOT EXISTS mfa bool NOT NULL DEFAULT false;
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS auth.oidc_states (
+    state text PRIMARY KEY,
+    code_verifier text NOT NULL,
+    nonce text NOT NULL,
+    keep_login bool NOT NULL,
+    use_cookies bool NOT NULL,
+    expires_at timestamp with time zone NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS auth.user_identities (
+    issuer text NOT NULL,
+    subject text NOT NULL,
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_idx ON auth.user_identities(user_id);
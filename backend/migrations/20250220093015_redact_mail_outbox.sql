@@ -0,0 +1,2 @@
+-- bodies can carry verification and password reset tokens, so only keep them while unsent
+UPDATE records.mail_outbox SET text_body = '', html_body = NULL WHERE status <> 'pending';
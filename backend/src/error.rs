@@ -1,7 +1,7 @@
-use crate::http::AuthError;
+use crate::http::{AuthError, BookingError};
 use axum::{
     extract::Json,
-    http::StatusCode,
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 use derive_more::From;
@@ -16,9 +16,18 @@ pub enum Error {
 
     UnprocessableEntity(String),
 
+    NotFound(String),
+
+    Forbidden(String),
+
+    TooManyRequests(u64), // seconds until the client may retry
+
     #[from]
     Auth(AuthError),
 
+    #[from]
+    Booking(BookingError),
+
     NotFoundTeamMembership(String),
 
     AddingTeam(String),
@@ -44,6 +53,21 @@ pub enum Error {
 
     #[from]
     DotEnv(dotenvy::Error),
+
+    #[from]
+    Io(std::io::Error),
+
+    #[from]
+    Join(tokio::task::JoinError),
+
+    #[from]
+    Mail(lettre::error::Error),
+
+    #[from]
+    MailAddress(lettre::address::AddressError),
+
+    #[from]
+    Smtp(lettre::transport::smtp::Error),
 }
 
 // region:    --- Error Boilerplate
@@ -63,20 +87,35 @@ impl IntoResponse for Error {
         use Error::*;
 
         let body = match &self {
-            Conflict(v) | UnprocessableEntity(v) | NotFoundTeamMembership(v) | AddingTeam(v) => {
+            Conflict(v)
+            | UnprocessableEntity(v)
+            | NotFound(v)
+            | Forbidden(v)
+            | NotFoundTeamMembership(v)
+            | AddingTeam(v) => {
                 format!("{:?}", v)
             }
             Auth(e) => format!("{:?}", e),
+            Booking(e) => format!("{:?}", e),
             Sqlx(e) => format!("{:?}", e),
             Validator(e) => format!("{:?}", e),
             PasswordHash(e) => format!("{:?}", e),
             Reqwest(e) => format!("{:?}", e),
             InvalidHeader(e) => format!("{:?}", e),
             DotEnv(e) => format!("{:?}", e),
+            Io(e) => format!("{:?}", e),
+            Join(e) => format!("{:?}", e),
+            TooManyRequests(retry_after) => format!("Too many requests, retry in {}s", retry_after),
             _ => String::from("Unknown error thrown"),
         };
         event!(Level::ERROR, body);
-        (self.status_code(), Json(body)).into_response()
+        let mut response = (self.status_code(), Json(body)).into_response();
+        if let TooManyRequests(retry_after) = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
@@ -85,13 +124,38 @@ impl Error {
         use Error::*;
 
         match self {
-            Sqlx(_) | PasswordHash(_) | DotEnv(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Sqlx(_) | PasswordHash(_) | DotEnv(_) | Io(_) | Join(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
             Validator(_) | UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Conflict(_) => StatusCode::CONFLICT,
-            Auth(AuthError::WrongCredentials) => StatusCode::UNAUTHORIZED,
+            NotFound(_) => StatusCode::NOT_FOUND,
+            TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Auth(
+                AuthError::WrongCredentials | AuthError::TokenRevoked | AuthError::TokenReused,
+            ) => StatusCode::UNAUTHORIZED,
+            Auth(
+                AuthError::NotAdmin
+                | AuthError::MfaRequired
+                | AuthError::InsufficientTier { .. }
+                | AuthError::MissingPermission { .. }
+                | AuthError::MissingScope { .. }
+                | AuthError::ApiKeyNotAllowed,
+            )
+            | Forbidden(_) => StatusCode::FORBIDDEN,
             Auth(AuthError::MissingCredentials | AuthError::InvalidToken) | InvalidHeader(_) => {
                 StatusCode::BAD_REQUEST
             }
+            Booking(BookingError::TierTooLow | BookingError::NotOpenYet { .. }) => {
+                StatusCode::FORBIDDEN
+            }
+            Booking(
+                BookingError::NotBooked
+                | BookingError::NotWaitlisted
+                | BookingError::NotEntered
+                | BookingError::NoUpcomingOccurrence,
+            ) => StatusCode::NOT_FOUND,
+            Booking(_) => StatusCode::CONFLICT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -0,0 +1,279 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::http::token::{AccessClaims, AuthError};
+use crate::http::{AppState, RequireAdmin};
+use crate::{Error, Result};
+
+/// Tells API keys apart from access tokens in the `Authorization` header
+pub const KEY_PREFIX: &str = "icsm_";
+
+/// Leading characters of a key kept in clear, so that admins can tell keys apart
+const DISPLAY_LENGTH: usize = 12;
+
+/// `last_used_at` is only written once a minute for a busy key
+const LAST_USED_PRECISION_SECONDS: i64 = 60;
+
+/// A scope an API key can be given, required with `RequireScope`
+pub trait Scope {
+    const NAME: &'static str;
+}
+
+macro_rules! scope {
+    ($(#[$doc:meta])* $marker:ident, $name:literal) => {
+        $(#[$doc])*
+        pub struct $marker;
+
+        impl Scope for $marker {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+scope!(
+    /// See sessions and their occurrences
+    SessionsRead,
+    "sessions:read"
+);
+scope!(
+    /// See the key owner's bookings and waitlist places
+    BookingsRead,
+    "bookings:read"
+);
+scope!(
+    /// Book and cancel on behalf of the key owner
+    BookingsWrite,
+    "bookings:write"
+);
+scope!(
+    /// See who is booked on the sessions the key owner runs
+    SessionBookingsRead,
+    "session_bookings:read"
+);
+
+const SCOPES: [&str; 4] = [
+    SessionsRead::NAME,
+    BookingsRead::NAME,
+    BookingsWrite::NAME,
+    SessionBookingsRead::NAME,
+];
+
+/// Put in the request extensions by `mid_jwt_auth` next to the claims when the request was
+/// made with an API key. Such requests only get through `RequireScope`.
+#[derive(Debug, Clone)]
+pub struct ApiKeyScopes(pub Vec<String>);
+
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// The only time the key itself is shown
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct NewApiKey {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    #[validate(length(min = 1), custom(function = "validate_scopes"))]
+    pub scopes: Vec<String>,
+    #[validate(custom(function = "validate_expiry"))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+fn validate_scopes(scopes: &[String]) -> std::result::Result<(), ValidationError> {
+    if scopes.iter().all(|scope| SCOPES.contains(&scope.as_str())) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("unknown_scope"))
+    }
+}
+
+fn validate_expiry(expires_at: &DateTime<Utc>) -> std::result::Result<(), ValidationError> {
+    if *expires_at > Utc::now() {
+        Ok(())
+    } else {
+        Err(ValidationError::new("expiry_in_past"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyFilter {
+    pub user_id: Option<Uuid>,
+    #[serde(default)]
+    pub include_revoked: bool,
+}
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/api-keys", get(list_api_keys))
+        .route("/api-keys/:id", delete(revoke_api_key))
+        .route("/users/:id/api-keys", post(create_api_key))
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Looks up a key presented in place of an access token. The key acts as its owner, without
+/// admin rights or role permissions, and only where its scopes allow.
+pub async fn authenticate(pool: &sqlx::PgPool, key: &str) -> Result<(AccessClaims, ApiKeyScopes)> {
+    let row = sqlx::query!(
+        r#"
+        SELECT k.id, k.scopes, k.expires_at, u.id AS user_id, u.shortcode, u.first_name, u.surname, u.tier
+        FROM auth.api_keys k
+        JOIN auth.users u ON u.id = k.user_id
+        WHERE k.key_hash = $1
+            AND k.revoked_at IS NULL
+            AND (k.expires_at IS NULL OR k.expires_at > CURRENT_TIMESTAMP)
+        "#,
+        hash_key(key)
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| {
+        error!(name: "exception_error", "Unknown, expired or revoked API key");
+        AuthError::InvalidToken
+    })?;
+
+    sqlx::query!(
+        r#"
+        UPDATE auth.api_keys SET last_used_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < $2)
+        "#,
+        row.id,
+        Utc::now() - Duration::seconds(LAST_USED_PRECISION_SECONDS)
+    )
+    .execute(pool)
+    .await?;
+
+    let claims = AccessClaims {
+        sub: row.shortcode,
+        exp: row
+            .expires_at
+            .unwrap_or_else(|| Utc::now() + Duration::hours(1))
+            .timestamp() as usize,
+        user_id: row.user_id,
+        name: row.first_name + &row.surname,
+        tier: row.tier,
+        admin: false,
+        sid: None,
+        permissions: vec![],
+        mfa: false,
+    };
+    Ok((claims, ApiKeyScopes(row.scopes)))
+}
+
+/// Issues a key for a user, e.g. the account a bot runs as
+async fn create_api_key(
+    State(pool): State<sqlx::PgPool>,
+    RequireAdmin(claims): RequireAdmin,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<NewApiKey>,
+) -> Result<(StatusCode, Json<CreatedApiKey>)> {
+    payload.validate()?;
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let key = format!("{KEY_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes));
+
+    let mut scopes = payload.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let api_key = sqlx::query_as!(
+        ApiKey,
+        r#"
+        INSERT INTO auth.api_keys(user_id, name, prefix, key_hash, scopes, expires_at, created_by)
+        SELECT id, $2, $3, $4, $5, $6, $7 FROM auth.users WHERE id = $1
+        RETURNING id, user_id, name, prefix, scopes, expires_at, last_used_at, created_by, created_at, revoked_at
+        "#,
+        user_id,
+        payload.name,
+        &key[..DISPLAY_LENGTH],
+        hash_key(&key),
+        &scopes,
+        payload.expires_at,
+        claims.user_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("User not found".into()))?;
+
+    info!(
+        "Admin {} created API key {} for {}",
+        claims.user_id, api_key.id, user_id
+    );
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { api_key, key })))
+}
+
+async fn list_api_keys(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Query(filter): Query<ApiKeyFilter>,
+) -> Result<Json<Vec<ApiKey>>> {
+    let api_keys = sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT id, user_id, name, prefix, scopes, expires_at, last_used_at, created_by, created_at, revoked_at
+        FROM auth.api_keys
+        WHERE ($1::uuid IS NULL OR user_id = $1)
+            AND ($2 OR revoked_at IS NULL)
+        ORDER BY created_at DESC
+        "#,
+        filter.user_id,
+        filter.include_revoked
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(api_keys))
+}
+
+/// Revoked keys stop working straight away, and are kept for the record
+async fn revoke_api_key(
+    State(pool): State<sqlx::PgPool>,
+    RequireAdmin(claims): RequireAdmin,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let revoked = sqlx::query!(
+        "UPDATE auth.api_keys SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1 AND revoked_at IS NULL",
+        id
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+    if revoked == 0 {
+        return Err(Error::NotFound(
+            "API key not found or already revoked".into(),
+        ));
+    }
+
+    info!("Admin {} revoked API key {}", claims.user_id, id);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,484 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as SqlJson;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::http::bookings::{self, BookingError, OccurrenceQuery};
+use crate::http::occurrences::{self, Occurrence};
+use crate::http::sessions::{self, SessionForm};
+use crate::http::token::AccessClaims;
+use crate::http::{AppState, CurrentUser, RequireScope, RequireTier, SessionsRead};
+use crate::{Error, Result};
+
+/// How far back lost ballots count towards the `losses` weighting
+const LOSSES_LOOKBACK_DAYS: i32 = 56;
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct BallotEntry {
+    pub user_id: Uuid,
+    pub occurrence_id: Uuid,
+    pub tier: i16,
+    pub outcome: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entrant {
+    pub user_id: Uuid,
+    pub weight: i64,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct BallotDraw {
+    pub occurrence_id: Uuid,
+    pub seed: i64,
+    pub weighting: String,
+    pub places: Option<i32>,
+    pub entrants: SqlJson<Vec<Entrant>>,
+    pub allocated: Vec<Uuid>,
+    pub waitlisted: Vec<Uuid>,
+    pub drawn_by: Option<Uuid>,
+    pub drawn_at: DateTime<Utc>,
+}
+
+/// A stored draw along with whether re-running it from its seed gives the same result
+#[derive(Debug, Serialize)]
+pub struct BallotAudit {
+    #[serde(flatten)]
+    pub draw: BallotDraw,
+    pub reproduced: bool,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:id/ballot", get(get_my_entry).delete(withdraw))
+        .route(
+            "/:id/occurrences/:occurrence_id/draw",
+            get(get_draw).post(run_draw),
+        )
+}
+
+/// Orders entrants by drawing them one at a time without replacement, each with a chance
+/// proportional to their weight, and fills `places` from the front. Entrants must be in a
+/// stable order, as the same order, weights and seed always give the same result.
+pub fn allocate(entrants: &[Entrant], places: Option<usize>, seed: u64) -> (Vec<Uuid>, Vec<Uuid>) {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut remaining: Vec<&Entrant> = entrants.iter().collect();
+    let mut drawn = Vec::with_capacity(entrants.len());
+
+    while !remaining.is_empty() {
+        let total: i64 = remaining.iter().map(|e| e.weight.max(1)).sum();
+        let mut ticket = rng.gen_range(0..total);
+        let mut index = 0;
+        for (i, entrant) in remaining.iter().enumerate() {
+            let weight = entrant.weight.max(1);
+            if ticket < weight {
+                index = i;
+                break;
+            }
+            ticket -= weight;
+        }
+        drawn.push(remaining.remove(index).user_id);
+    }
+
+    let places = places.unwrap_or(drawn.len()).min(drawn.len());
+    let waitlisted = drawn.split_off(places);
+    (drawn, waitlisted)
+}
+
+pub async fn find_draw(
+    conn: &mut sqlx::PgConnection,
+    occurrence_id: Uuid,
+) -> Result<Option<BallotDraw>> {
+    let draw = sqlx::query_as!(
+        BallotDraw,
+        r#"
+        SELECT occurrence_id, seed, weighting, places,
+            entrants AS "entrants: SqlJson<Vec<Entrant>>", allocated, waitlisted, drawn_by, drawn_at
+        FROM records.ballot_draws
+        WHERE occurrence_id = $1
+        "#,
+        occurrence_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(draw)
+}
+
+/// Registers interest in an occurrence whose ballot has not been drawn yet
+pub async fn enter(
+    conn: &mut sqlx::PgConnection,
+    claims: &AccessClaims,
+    occurrence: &Occurrence,
+    form: &SessionForm,
+) -> Result<BallotEntry> {
+    let closes_at = form.ballot_closes_at(occurrence.start_time);
+    if Utc::now() >= closes_at {
+        return Err(BookingError::BallotClosed {
+            closed_at: closes_at,
+        }
+        .into());
+    }
+
+    let entry = sqlx::query_as!(
+        BallotEntry,
+        r#"
+        INSERT INTO records.ballot_entries(user_id, occurrence_id, tier)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        RETURNING *
+        "#,
+        claims.user_id,
+        occurrence.id,
+        claims.tier
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(BookingError::AlreadyEntered)?;
+
+    Ok(entry)
+}
+
+/// Draws the ballot of a locked occurrence, booking the winners and queueing everyone else on
+/// the waitlist in the order they were drawn
+pub async fn draw(
+    conn: &mut sqlx::PgConnection,
+    occurrence: &Occurrence,
+    form: &SessionForm,
+    seed: i64,
+    drawn_by: Option<Uuid>,
+) -> Result<BallotDraw> {
+    if find_draw(conn, occurrence.id).await?.is_some() {
+        return Err(BookingError::BallotDrawn.into());
+    }
+
+    let entrants = sqlx::query_as!(
+        Entrant,
+        r#"
+        SELECT e.user_id,
+            CASE $2::text
+                WHEN 'tier' THEN 1 + e.tier
+                WHEN 'losses' THEN 1 + (
+                    SELECT COUNT(*) FROM records.ballot_entries l
+                    JOIN records.ballot_draws d ON d.occurrence_id = l.occurrence_id
+                    WHERE l.user_id = e.user_id AND l.outcome = 'waitlisted'
+                        AND d.drawn_at > now() - make_interval(days => $3)
+                )
+                ELSE 1
+            END AS "weight!"
+        FROM records.ballot_entries e
+        WHERE e.occurrence_id = $1
+        ORDER BY e.user_id
+        "#,
+        occurrence.id,
+        form.ballot_weighting,
+        LOSSES_LOOKBACK_DAYS
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let places = match occurrence.user_limit {
+        Some(limit) => {
+            let booked = sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM records.bookings WHERE occurrence_id = $1"#,
+                occurrence.id
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            Some((i64::from(limit) - booked).max(0) as i32)
+        }
+        None => None,
+    };
+    let (allocated, waitlisted) = allocate(&entrants, places.map(|p| p as usize), seed as u64);
+
+    sqlx::query!(
+        r#"
+        INSERT INTO records.bookings(user_id, occurrence_id)
+        SELECT user_id, $2 FROM UNNEST($1::uuid[]) AS user_id
+        ON CONFLICT DO NOTHING
+        "#,
+        &allocated[..],
+        occurrence.id
+    )
+    .execute(&mut *conn)
+    .await?;
+    bookings::queue_booking_emails(conn, occurrence, &allocated, false).await?;
+
+    // spread the timestamps so the waitlist keeps the drawn order
+    sqlx::query!(
+        r#"
+        INSERT INTO records.waitlist(user_id, occurrence_id, created_at)
+        SELECT user_id, $2, clock_timestamp() + position * interval '1 microsecond'
+        FROM UNNEST($1::uuid[]) WITH ORDINALITY AS w(user_id, position)
+        ON CONFLICT DO NOTHING
+        "#,
+        &waitlisted[..],
+        occurrence.id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE records.ballot_entries
+        SET outcome = CASE WHEN user_id = ANY($2) THEN 'allocated' ELSE 'waitlisted' END
+        WHERE occurrence_id = $1
+        "#,
+        occurrence.id,
+        &allocated[..]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let draw = sqlx::query_as!(
+        BallotDraw,
+        r#"
+        INSERT INTO records.ballot_draws(occurrence_id, seed, weighting, places, entrants, allocated, waitlisted, drawn_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING occurrence_id, seed, weighting, places,
+            entrants AS "entrants: SqlJson<Vec<Entrant>>", allocated, waitlisted, drawn_by, drawn_at
+        "#,
+        occurrence.id,
+        seed,
+        form.ballot_weighting,
+        places,
+        SqlJson(&entrants) as _,
+        &allocated[..],
+        &waitlisted[..],
+        drawn_by
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    info!(
+        "Drew ballot for occurrence {} with seed {}: {} allocated, {} waitlisted",
+        occurrence.id,
+        seed,
+        draw.allocated.len(),
+        draw.waitlisted.len()
+    );
+    Ok(draw)
+}
+
+/// Draws every ballot whose entries have closed, with a random seed
+pub async fn draw_due_ballots(pool: &sqlx::PgPool) -> Result<()> {
+    let due = sqlx::query!(
+        r#"
+        SELECT o.id AS "id!", o.form_id AS "form_id!"
+        FROM records.effective_occurrences o
+        JOIN records.session_forms f ON f.id = o.form_id
+        WHERE f.allocation = 'ballot' AND NOT o.cancelled
+            AND o.start_time - COALESCE(f.ballot_cutoff, interval '0') <= now()
+            AND NOT EXISTS (
+                SELECT 1 FROM records.ballot_draws d WHERE d.occurrence_id = o.id
+            )
+            AND o.start_time > now() - interval '1 day'
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for occurrence in due {
+        let mut tx = pool.begin().await?;
+        let locked = lock_occurrence(&mut tx, occurrence.form_id, occurrence.id).await?;
+        let form = fetch_form(&mut tx, occurrence.form_id).await?;
+        match draw(&mut tx, &locked, &form, rand::random(), None).await {
+            Ok(_) => tx.commit().await?,
+            Err(e) => {
+                error!(name: "ballot_error", "Cannot draw ballot for {}: {}", occurrence.id, e)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn lock_occurrence(
+    conn: &mut sqlx::PgConnection,
+    form_id: Uuid,
+    occurrence_id: Uuid,
+) -> Result<Occurrence> {
+    sqlx::query!(
+        "SELECT id FROM records.session_occurrences WHERE id = $1 AND form_id = $2 FOR UPDATE",
+        occurrence_id,
+        form_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound("Occurrence not found".into()))?;
+
+    occurrences::fetch_occurrence(conn, form_id, occurrence_id).await
+}
+
+async fn fetch_form(conn: &mut sqlx::PgConnection, id: Uuid) -> Result<SessionForm> {
+    sqlx::query_as!(
+        SessionForm,
+        "SELECT * FROM records.session_forms WHERE id = $1",
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound("Session not found".into()))
+}
+
+/// Draws a ballot early, once entries have closed but before the scheduled draw gets to it.
+/// The seed is always random, so that no one can pick the outcome.
+async fn run_draw(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path((id, occurrence_id)): Path<(Uuid, Uuid)>,
+) -> Result<(StatusCode, Json<BallotDraw>)> {
+    let mut tx = pool.begin().await?;
+    let occurrence = lock_occurrence(&mut tx, id, occurrence_id).await?;
+    let form = fetch_form(&mut tx, id).await?;
+    sessions::check_author(&claims, form.author_id)?;
+    if !form.is_ballot() {
+        return Err(BookingError::NotBallot.into());
+    }
+    let closes_at = form.ballot_closes_at(occurrence.start_time);
+    if Utc::now() < closes_at {
+        return Err(BookingError::BallotOpen { closes_at }.into());
+    }
+
+    let draw = draw(
+        &mut tx,
+        &occurrence,
+        &form,
+        rand::random(),
+        Some(claims.user_id),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(draw)))
+}
+
+/// The stored draw, with whether it can be reproduced from its seed. It names every entrant,
+/// so it is only shown to members.
+async fn get_draw(
+    State(pool): State<sqlx::PgPool>,
+    _scope: RequireScope<SessionsRead>,
+    _member: RequireTier<1>,
+    Path((id, occurrence_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<BallotAudit>> {
+    let mut conn = pool.acquire().await?;
+    occurrences::fetch_occurrence(&mut conn, id, occurrence_id).await?;
+    let draw = find_draw(&mut conn, occurrence_id)
+        .await?
+        .ok_or_else(|| Error::NotFound("Ballot has not been drawn".into()))?;
+
+    let places = draw.places.map(|p| p as usize);
+    let (allocated, waitlisted) = allocate(&draw.entrants, places, draw.seed as u64);
+    let reproduced = allocated == draw.allocated && waitlisted == draw.waitlisted;
+
+    Ok(Json(BallotAudit { draw, reproduced }))
+}
+
+async fn get_my_entry(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<Json<BallotEntry>> {
+    let mut tx = pool.begin().await?;
+    let occurrence = bookings::lock_occurrence(&mut tx, id, query.occurrence).await?;
+    let entry = sqlx::query_as!(
+        BallotEntry,
+        "SELECT * FROM records.ballot_entries WHERE user_id = $1 AND occurrence_id = $2",
+        claims.user_id,
+        occurrence.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(BookingError::NotEntered)?;
+    tx.commit().await?;
+
+    Ok(Json(entry))
+}
+
+/// Withdraws from a ballot before it is drawn; afterwards the place or waitlist entry the
+/// draw produced has to be given up instead
+async fn withdraw(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<StatusCode> {
+    let mut tx = pool.begin().await?;
+    let occurrence = bookings::lock_occurrence(&mut tx, id, query.occurrence).await?;
+    if find_draw(&mut tx, occurrence.id).await?.is_some() {
+        return Err(BookingError::BallotDrawn.into());
+    }
+
+    let withdrawn = sqlx::query!(
+        "DELETE FROM records.ballot_entries WHERE user_id = $1 AND occurrence_id = $2",
+        claims.user_id,
+        occurrence.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if withdrawn.rows_affected() == 0 {
+        return Err(BookingError::NotEntered.into());
+    }
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entrants(weights: &[i64]) -> Vec<Entrant> {
+        weights
+            .iter()
+            .map(|&weight| Entrant {
+                user_id: Uuid::new_v4(),
+                weight,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_draw() {
+        let entrants = entrants(&[1, 2, 3, 1, 5, 1]);
+        assert_eq!(
+            allocate(&entrants, Some(3), 42),
+            allocate(&entrants, Some(3), 42)
+        );
+    }
+
+    #[test]
+    fn places_are_filled_and_the_rest_waitlisted() {
+        let entrants = entrants(&[1; 6]);
+        let (allocated, waitlisted) = allocate(&entrants, Some(4), 7);
+        assert_eq!((allocated.len(), waitlisted.len()), (4, 2));
+
+        let mut drawn: Vec<Uuid> = allocated.into_iter().chain(waitlisted).collect();
+        let mut everyone: Vec<Uuid> = entrants.iter().map(|e| e.user_id).collect();
+        drawn.sort();
+        everyone.sort();
+        assert_eq!(drawn, everyone);
+
+        let (allocated, waitlisted) = allocate(&entrants, Some(10), 7);
+        assert_eq!((allocated.len(), waitlisted.len()), (6, 0));
+    }
+
+    #[test]
+    fn no_limit_allocates_everyone() {
+        let entrants = entrants(&[3, 1, 2]);
+        let (allocated, waitlisted) = allocate(&entrants, None, 1);
+        assert_eq!(allocated.len(), 3);
+        assert!(waitlisted.is_empty());
+    }
+}
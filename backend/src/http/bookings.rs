@@ -0,0 +1,535 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::http::ballots;
+use crate::http::occurrences::{self, Occurrence};
+use crate::http::sessions::SessionForm;
+use crate::http::{
+    AppState, BookingsRead, BookingsWrite, ManageSessions, Permission, RequireScope,
+    SessionBookingsRead,
+};
+use crate::mail::{outbox, templates};
+use crate::{Error, Result};
+
+#[derive(Debug)]
+pub enum BookingError {
+    AlreadyBooked,
+    AlreadyWaitlisted,
+    NotBooked,
+    NotWaitlisted,
+    TierTooLow,
+    NotOpenYet { opens_at: DateTime<Utc> },
+    SessionCancelled,
+    SessionStarted,
+    NoUpcomingOccurrence,
+    NotBallot,
+    AlreadyEntered,
+    NotEntered,
+    BallotClosed { closed_at: DateTime<Utc> },
+    BallotOpen { closes_at: DateTime<Utc> },
+    BallotDrawn,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct Booking {
+    pub user_id: Uuid,
+    pub occurrence_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub promoted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct WaitlistEntry {
+    pub occurrence_id: Uuid,
+    pub position: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct BookingDetails {
+    pub occurrence_id: Uuid,
+    pub form_id: Uuid,
+    pub title: String,
+    pub location: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub cancelled: bool,
+    pub created_at: DateTime<Utc>,
+    pub promoted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct WaitlistDetails {
+    pub occurrence_id: Uuid,
+    pub form_id: Uuid,
+    pub title: String,
+    pub start_time: DateTime<Utc>,
+    pub position: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A booking on a session as its organisers see it
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct SessionBooking {
+    pub occurrence_id: Uuid,
+    pub start_time: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub shortcode: String,
+    pub first_name: String,
+    pub surname: String,
+    pub created_at: DateTime<Utc>,
+    pub promoted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OccurrenceQuery {
+    pub occurrence: Option<Uuid>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:id/book", post(book).delete(cancel_booking))
+        .route("/:id/bookings", get(list_session_bookings))
+        .route(
+            "/:id/waitlist",
+            get(get_waitlist_position).delete(leave_waitlist),
+        )
+}
+
+pub fn me_router() -> Router<AppState> {
+    Router::new()
+        .route("/bookings", get(list_my_bookings))
+        .route("/waitlist", get(list_my_waitlist))
+}
+
+/// Picks the requested occurrence of a series, or the next one that has not started yet, and
+/// locks it for the rest of the transaction so its capacity checks cannot interleave.
+pub async fn lock_occurrence(
+    conn: &mut sqlx::PgConnection,
+    form_id: Uuid,
+    occurrence_id: Option<Uuid>,
+) -> Result<Occurrence> {
+    let occurrence_id = match occurrence_id {
+        Some(id) => id,
+        None => sqlx::query_scalar!(
+            r#"
+            SELECT id AS "id!" FROM records.effective_occurrences
+            WHERE form_id = $1 AND start_time > now() AND NOT cancelled
+            ORDER BY start_time
+            LIMIT 1
+            "#,
+            form_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(BookingError::NoUpcomingOccurrence)?,
+    };
+
+    sqlx::query!(
+        "SELECT id FROM records.session_occurrences WHERE id = $1 AND form_id = $2 FOR UPDATE",
+        occurrence_id,
+        form_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound("Occurrence not found".into()))?;
+
+    occurrences::fetch_occurrence(conn, form_id, occurrence_id).await
+}
+
+async fn waitlist_position(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+    occurrence_id: Uuid,
+) -> Result<Option<WaitlistEntry>> {
+    let entry = sqlx::query_as!(
+        WaitlistEntry,
+        r#"
+        SELECT occurrence_id, position AS "position!", created_at FROM (
+            SELECT user_id, occurrence_id, created_at,
+                ROW_NUMBER() OVER (ORDER BY created_at, user_id) AS position
+            FROM records.waitlist
+            WHERE occurrence_id = $2
+        ) w
+        WHERE user_id = $1
+        "#,
+        user_id,
+        occurrence_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(entry)
+}
+
+/// Moves users from the front of the waitlist into any free places on an occurrence, which
+/// must already be locked by the caller. Returns the users that were promoted.
+pub async fn promote_waitlist(
+    conn: &mut sqlx::PgConnection,
+    occurrence: &Occurrence,
+) -> Result<Vec<Uuid>> {
+    if occurrence.cancelled || occurrence.start_time <= Utc::now() {
+        return Ok(Vec::new());
+    }
+
+    let free = match occurrence.user_limit {
+        Some(limit) => {
+            let booked = sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM records.bookings WHERE occurrence_id = $1"#,
+                occurrence.id
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            (i64::from(limit) - booked).max(0)
+        }
+        None => i64::MAX,
+    };
+    if free == 0 {
+        return Ok(Vec::new());
+    }
+
+    let promoted = sqlx::query_scalar!(
+        r#"
+        WITH promoted AS (
+            DELETE FROM records.waitlist
+            WHERE occurrence_id = $1 AND user_id IN (
+                SELECT user_id FROM records.waitlist
+                WHERE occurrence_id = $1
+                ORDER BY created_at, user_id
+                LIMIT $2
+            )
+            RETURNING user_id
+        )
+        INSERT INTO records.bookings(user_id, occurrence_id, promoted_at)
+        SELECT user_id, $1, CURRENT_TIMESTAMP FROM promoted
+        RETURNING user_id AS "user_id!"
+        "#,
+        occurrence.id,
+        free
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if !promoted.is_empty() {
+        info!(
+            "Promoted {} users from the waitlist of occurrence {}",
+            promoted.len(),
+            occurrence.id
+        );
+        queue_booking_emails(conn, occurrence, &promoted, true).await?;
+    }
+    Ok(promoted)
+}
+
+/// Queues an email to each user telling them they have a place on the occurrence, either
+/// straight away or after `promoted` off the waitlist
+pub async fn queue_booking_emails(
+    conn: &mut sqlx::PgConnection,
+    occurrence: &Occurrence,
+    user_ids: &[Uuid],
+    promoted: bool,
+) -> Result<()> {
+    let recipients = sqlx::query!(
+        r#"
+        SELECT u.shortcode, u.first_name, f.title
+        FROM auth.users u, records.session_forms f
+        WHERE u.id = ANY($1) AND f.id = $2
+        "#,
+        user_ids,
+        occurrence.form_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for recipient in recipients {
+        let template = if promoted {
+            templates::waitlist_promoted
+        } else {
+            templates::booking_confirmed
+        };
+        let message = template(
+            &recipient.shortcode,
+            &recipient.first_name,
+            &recipient.title,
+            &occurrence.location,
+            occurrence.start_time,
+        );
+        outbox::enqueue(conn, &message).await?;
+    }
+    Ok(())
+}
+
+async fn book(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<BookingsWrite>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<Response> {
+    let mut tx = pool.begin().await?;
+    let occurrence = lock_occurrence(&mut tx, id, query.occurrence).await?;
+
+    if occurrence.cancelled {
+        return Err(BookingError::SessionCancelled.into());
+    }
+    if occurrence.start_time <= Utc::now() {
+        return Err(BookingError::SessionStarted.into());
+    }
+    if claims.tier < occurrence.tier {
+        return Err(BookingError::TierTooLow.into());
+    }
+
+    let form = sqlx::query_as!(
+        SessionForm,
+        "SELECT * FROM records.session_forms WHERE id = $1",
+        occurrence.form_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if let Some(opens_at) = form.booking_opens_at(claims.tier, occurrence.start_time) {
+        if Utc::now() < opens_at {
+            return Err(BookingError::NotOpenYet { opens_at }.into());
+        }
+    }
+
+    // ballot sessions take entries until the draw, then fill up like any other session
+    if form.is_ballot() && ballots::find_draw(&mut tx, occurrence.id).await?.is_none() {
+        let entry = ballots::enter(&mut tx, &claims, &occurrence, &form).await?;
+        tx.commit().await?;
+        return Ok((StatusCode::ACCEPTED, Json(entry)).into_response());
+    }
+
+    let already_booked = sqlx::query_scalar!(
+        "SELECT user_id FROM records.bookings WHERE user_id = $1 AND occurrence_id = $2",
+        claims.user_id,
+        occurrence.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if already_booked.is_some() {
+        return Err(BookingError::AlreadyBooked.into());
+    }
+
+    let booked = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM records.bookings WHERE occurrence_id = $1"#,
+        occurrence.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let waiting = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM records.waitlist WHERE occurrence_id = $1"#,
+        occurrence.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // join the waitlist when full, or when others are already queueing for a place
+    let full = occurrence
+        .user_limit
+        .is_some_and(|limit| booked >= i64::from(limit));
+    if full || waiting > 0 {
+        let joined = sqlx::query!(
+            r#"
+            INSERT INTO records.waitlist(user_id, occurrence_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            claims.user_id,
+            occurrence.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if joined.rows_affected() == 0 {
+            return Err(BookingError::AlreadyWaitlisted.into());
+        }
+        let entry = waitlist_position(&mut tx, claims.user_id, occurrence.id)
+            .await?
+            .ok_or(BookingError::NotWaitlisted)?;
+        tx.commit().await?;
+        return Ok((StatusCode::ACCEPTED, Json(entry)).into_response());
+    }
+
+    let booking = sqlx::query_as!(
+        Booking,
+        r#"
+        INSERT INTO records.bookings(user_id, occurrence_id)
+        VALUES ($1, $2)
+        RETURNING user_id AS "user_id!", occurrence_id, created_at, promoted_at
+        "#,
+        claims.user_id,
+        occurrence.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    queue_booking_emails(&mut tx, &occurrence, &[claims.user_id], false).await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(booking)).into_response())
+}
+
+async fn cancel_booking(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<BookingsWrite>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<Json<Booking>> {
+    let mut tx = pool.begin().await?;
+    let occurrence = lock_occurrence(&mut tx, id, query.occurrence).await?;
+
+    let booking = sqlx::query_as!(
+        Booking,
+        r#"
+        DELETE FROM records.bookings
+        WHERE user_id = $1 AND occurrence_id = $2
+        RETURNING user_id AS "user_id!", occurrence_id, created_at, promoted_at
+        "#,
+        claims.user_id,
+        occurrence.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(BookingError::NotBooked)?;
+    promote_waitlist(&mut tx, &occurrence).await?;
+    tx.commit().await?;
+
+    Ok(Json(booking))
+}
+
+async fn get_waitlist_position(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<BookingsRead>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<Json<WaitlistEntry>> {
+    let mut tx = pool.begin().await?;
+    let occurrence = lock_occurrence(&mut tx, id, query.occurrence).await?;
+    let entry = waitlist_position(&mut tx, claims.user_id, occurrence.id)
+        .await?
+        .ok_or(BookingError::NotWaitlisted)?;
+    tx.commit().await?;
+
+    Ok(Json(entry))
+}
+
+async fn leave_waitlist(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<BookingsWrite>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<StatusCode> {
+    let mut tx = pool.begin().await?;
+    let occurrence = lock_occurrence(&mut tx, id, query.occurrence).await?;
+    let left = sqlx::query!(
+        "DELETE FROM records.waitlist WHERE user_id = $1 AND occurrence_id = $2",
+        claims.user_id,
+        occurrence.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if left.rows_affected() == 0 {
+        return Err(BookingError::NotWaitlisted.into());
+    }
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_my_bookings(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<BookingsRead>,
+) -> Result<Json<Vec<BookingDetails>>> {
+    let bookings = sqlx::query_as!(
+        BookingDetails,
+        r#"
+        SELECT b.occurrence_id, o.form_id AS "form_id!", f.title, o.location AS "location!",
+            o.start_time AS "start_time!", o.end_time AS "end_time!",
+            o.cancelled AS "cancelled!", b.created_at, b.promoted_at
+        FROM records.bookings b
+        JOIN records.effective_occurrences o ON o.id = b.occurrence_id
+        JOIN records.session_forms f ON f.id = o.form_id
+        WHERE b.user_id = $1
+        ORDER BY o.start_time
+        "#,
+        claims.user_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(bookings))
+}
+
+async fn list_my_waitlist(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<BookingsRead>,
+) -> Result<Json<Vec<WaitlistDetails>>> {
+    let entries = sqlx::query_as!(
+        WaitlistDetails,
+        r#"
+        SELECT w.occurrence_id, o.form_id AS "form_id!", f.title,
+            o.start_time AS "start_time!", w.position AS "position!", w.created_at
+        FROM (
+            SELECT user_id, occurrence_id, created_at,
+                ROW_NUMBER() OVER (PARTITION BY occurrence_id ORDER BY created_at, user_id) AS position
+            FROM records.waitlist
+        ) w
+        JOIN records.effective_occurrences o ON o.id = w.occurrence_id
+        JOIN records.session_forms f ON f.id = o.form_id
+        WHERE w.user_id = $1
+        ORDER BY o.start_time
+        "#,
+        claims.user_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(entries))
+}
+
+/// Everyone booked on a session, or on one of its occurrences, e.g. for the committee's
+/// spreadsheets. Only for the session's author and those who manage sessions.
+async fn list_session_bookings(
+    State(pool): State<sqlx::PgPool>,
+    RequireScope(claims, _): RequireScope<SessionBookingsRead>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<OccurrenceQuery>,
+) -> Result<Json<Vec<SessionBooking>>> {
+    let author_id = sqlx::query_scalar!(
+        "SELECT author_id FROM records.session_forms WHERE id = $1",
+        id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("Session not found".into()))?;
+    if claims.user_id != author_id && !claims.has_permission(ManageSessions::NAME) {
+        return Err(Error::Forbidden(
+            "Only the author of a session or a session coordinator can see its bookings".into(),
+        ));
+    }
+
+    let bookings = sqlx::query_as!(
+        SessionBooking,
+        r#"
+        SELECT b.occurrence_id, o.start_time AS "start_time!", b.user_id, u.shortcode,
+            u.first_name, u.surname, b.created_at, b.promoted_at
+        FROM records.bookings b
+        JOIN records.effective_occurrences o ON o.id = b.occurrence_id
+        JOIN auth.users u ON u.id = b.user_id
+        WHERE o.form_id = $1 AND ($2::uuid IS NULL OR o.id = $2)
+        ORDER BY o.start_time, b.created_at
+        "#,
+        id,
+        query.occurrence
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(bookings))
+}
@@ -0,0 +1,138 @@
+use axum::http::{HeaderMap, Method};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{Error, Result};
+
+pub const ACCESS_COOKIE: &str = "access_token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Readable by the frontend, which echoes it back in `CSRF_HEADER`
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Ties a single sign-on login to the browser that started it
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/// The refresh cookie is only ever sent to the endpoint that rotates it
+const REFRESH_PATH: &str = "/api/v1/users/refresh";
+const API_PATH: &str = "/api/v1";
+const OIDC_PATH: &str = "/api/v1/users/oidc";
+
+/// How a client wants to receive its tokens. Browsers should use cookies so that the tokens
+/// are out of reach of scripts; API clients keep getting them in the response body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Bearer,
+    Cookie,
+}
+
+fn token_cookie(name: &'static str, value: String, path: &'static str) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path(path)
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .build()
+}
+
+fn expiry(at: DateTime<Utc>) -> time::OffsetDateTime {
+    time::OffsetDateTime::from_unix_timestamp(at.timestamp()).expect("valid timestamp")
+}
+
+/// Sets the token cookies along with a fresh CSRF token
+pub fn with_tokens(
+    jar: CookieJar,
+    access_token: String,
+    access_expires_at: DateTime<Utc>,
+    refresh: Option<(String, DateTime<Utc>)>,
+) -> CookieJar {
+    let mut access = token_cookie(ACCESS_COOKIE, access_token, API_PATH);
+    access.set_expires(expiry(access_expires_at));
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let csrf = Cookie::build((CSRF_COOKIE, hex::encode(bytes)))
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .build();
+
+    let jar = jar.add(access).add(csrf);
+    match refresh {
+        Some((refresh_token, refresh_expires_at)) => {
+            let mut refresh = token_cookie(REFRESH_COOKIE, refresh_token, REFRESH_PATH);
+            refresh.set_expires(expiry(refresh_expires_at));
+            jar.add(refresh)
+        }
+        None => jar,
+    }
+}
+
+/// Removes every cookie set by `with_tokens`. The refresh cookie is never sent outside its own
+/// path, so removal cookies are added directly rather than through `CookieJar::remove`.
+pub fn cleared(jar: CookieJar) -> CookieJar {
+    [
+        (ACCESS_COOKIE, API_PATH),
+        (REFRESH_COOKIE, REFRESH_PATH),
+        (CSRF_COOKIE, "/"),
+    ]
+    .into_iter()
+    .fold(jar, |jar, (name, path)| {
+        let mut cookie = Cookie::build((name, "")).path(path).build();
+        cookie.make_removal();
+        jar.add(cookie)
+    })
+}
+
+/// Holds the `state` of a single sign-on login until the provider sends the browser back.
+/// `Lax` rather than `Strict`, as that return is a top-level navigation from another site.
+pub fn oidc_state(state: String, minutes: i64) -> Cookie<'static> {
+    Cookie::build((OIDC_STATE_COOKIE, state))
+        .path(OIDC_PATH)
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(minutes))
+        .build()
+}
+
+pub fn oidc_state_removal() -> Cookie<'static> {
+    let mut cookie = Cookie::build((OIDC_STATE_COOKIE, ""))
+        .path(OIDC_PATH)
+        .build();
+    cookie.make_removal();
+    cookie
+}
+
+/// Double-submit check for requests authenticated by cookie: another site can make the
+/// browser send our cookies, but it can't read the CSRF cookie to copy it into the header
+pub fn check_csrf(jar: &CookieJar, headers: &HeaderMap) -> Result<()> {
+    let cookie = jar.get(CSRF_COOKIE).map(|cookie| cookie.value());
+    let header = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header))
+            if !cookie.is_empty() && constant_time_eq(cookie.as_bytes(), header.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            error!(name: "exception_error", "CSRF token missing or mismatched");
+            Err(Error::Forbidden("CSRF token missing or invalid".into()))
+        }
+    }
+}
+
+/// Requests that can't change anything don't need a CSRF token
+pub fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
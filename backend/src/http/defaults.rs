@@ -5,3 +5,11 @@ pub fn default_uuid() -> uuid::Uuid {
 pub fn default_time() -> chrono::DateTime<chrono::Utc> {
     chrono::Utc::now()
 }
+
+pub fn default_allocation() -> String {
+    String::from("first_come")
+}
+
+pub fn default_ballot_weighting() -> String {
+    String::from("none")
+}
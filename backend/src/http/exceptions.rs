@@ -0,0 +1,272 @@
+use axum::{
+    extract::{Json, Path, State},
+    routing::{get, put},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::http::occurrences::{self, Occurrence};
+use crate::http::{bookings, sessions, AppState, CurrentUser, RequireScope, SessionsRead};
+use crate::mail::{outbox, templates};
+use crate::{Error, Result};
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct OccurrenceException {
+    pub occurrence_id: Uuid,
+    pub author_id: Uuid,
+    pub cancelled: bool,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub location: Option<String>,
+    pub user_limit: Option<i16>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "validate_exception_form"))]
+pub struct ExceptionForm {
+    #[serde(default)]
+    pub cancelled: bool,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    #[validate(length(min = 1))]
+    pub location: Option<String>,
+    #[validate(range(min = 1))]
+    pub user_limit: Option<i16>,
+    pub reason: Option<String>,
+}
+
+fn validate_exception_form(form: &ExceptionForm) -> std::result::Result<(), ValidationError> {
+    match (form.start_time, form.end_time) {
+        (Some(start_time), Some(end_time)) if end_time <= start_time => {
+            Err(ValidationError::new("end_time_before_start_time"))
+        }
+        (Some(_), None) | (None, Some(_)) => Err(ValidationError::new("times_incomplete")),
+        _ => Ok(()),
+    }
+}
+
+/// An exception together with the occurrence it produces and the bookings it moved around
+#[derive(Debug, Serialize)]
+pub struct ExceptionOutcome {
+    pub exception: OccurrenceException,
+    pub occurrence: Occurrence,
+    pub released: Vec<Uuid>,
+    pub promoted: Vec<Uuid>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:id/exceptions", get(list_exceptions))
+        .route(
+            "/:id/occurrences/:occurrence_id/exception",
+            put(put_exception).delete(delete_exception),
+        )
+}
+
+/// Releases the bookings that no longer fit an occurrence, keeping the earliest bookings
+/// when its limit has been lowered and clearing its waitlist when it is cancelled. Released
+/// users are emailed. Returns the users whose bookings were released.
+pub async fn release_bookings(
+    conn: &mut sqlx::PgConnection,
+    occurrence: &Occurrence,
+) -> Result<Vec<Uuid>> {
+    let keep = if occurrence.cancelled {
+        sqlx::query!(
+            "DELETE FROM records.waitlist WHERE occurrence_id = $1",
+            occurrence.id
+        )
+        .execute(&mut *conn)
+        .await?;
+        Some(0)
+    } else {
+        occurrence.user_limit.map(i64::from)
+    };
+    let Some(keep) = keep else {
+        return Ok(Vec::new());
+    };
+
+    let released = sqlx::query_scalar!(
+        r#"
+        DELETE FROM records.bookings
+        WHERE occurrence_id = $1 AND user_id NOT IN (
+            SELECT user_id FROM records.bookings
+            WHERE occurrence_id = $1
+            ORDER BY created_at
+            LIMIT $2
+        )
+        RETURNING user_id AS "user_id!"
+        "#,
+        occurrence.id,
+        keep
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if !released.is_empty() {
+        info!(
+            "Released {} bookings from occurrence {}",
+            released.len(),
+            occurrence.id
+        );
+        queue_release_emails(conn, occurrence, &released).await?;
+    }
+    Ok(released)
+}
+
+async fn queue_release_emails(
+    conn: &mut sqlx::PgConnection,
+    occurrence: &Occurrence,
+    user_ids: &[Uuid],
+) -> Result<()> {
+    let recipients = sqlx::query!(
+        r#"
+        SELECT u.shortcode, u.first_name, f.title
+        FROM auth.users u, records.session_forms f
+        WHERE u.id = ANY($1) AND f.id = $2
+        "#,
+        user_ids,
+        occurrence.form_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for recipient in recipients {
+        let message = templates::booking_released(
+            &recipient.shortcode,
+            &recipient.first_name,
+            &recipient.title,
+            &occurrence.location,
+            occurrence.start_time,
+            occurrence.cancelled,
+        );
+        outbox::enqueue(conn, &message).await?;
+    }
+    Ok(())
+}
+
+async fn list_exceptions(
+    State(pool): State<sqlx::PgPool>,
+    _scope: RequireScope<SessionsRead>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<OccurrenceException>>> {
+    let exceptions = sqlx::query_as!(
+        OccurrenceException,
+        r#"
+        SELECT e.* FROM records.occurrence_exceptions e
+        JOIN records.session_occurrences o ON o.id = e.occurrence_id
+        WHERE o.form_id = $1
+        ORDER BY o.seq
+        "#,
+        id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(exceptions))
+}
+
+/// Cancels, moves or overrides a single occurrence, replacing any earlier exception for it.
+/// Bookings follow the occurrence when it moves, and are released when it is cancelled or
+/// its limit drops below the number already booked. A raised limit promotes from the waitlist.
+async fn put_exception(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path((id, occurrence_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<ExceptionForm>,
+) -> Result<Json<ExceptionOutcome>> {
+    payload.validate()?;
+
+    let mut tx = pool.begin().await?;
+    sessions::authorize_edit(&mut tx, &claims, id).await?;
+    // lock the occurrence so bookings cannot be made against the old values meanwhile
+    sqlx::query!(
+        "SELECT id FROM records.session_occurrences WHERE id = $1 AND form_id = $2 FOR UPDATE",
+        occurrence_id,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::NotFound("Occurrence not found".into()))?;
+
+    let exception = sqlx::query_as!(
+        OccurrenceException,
+        r#"
+        INSERT INTO records.occurrence_exceptions(occurrence_id, author_id, cancelled, start_time, end_time, location, user_limit, reason)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (occurrence_id) DO UPDATE
+        SET author_id = EXCLUDED.author_id, cancelled = EXCLUDED.cancelled,
+            start_time = EXCLUDED.start_time, end_time = EXCLUDED.end_time,
+            location = EXCLUDED.location, user_limit = EXCLUDED.user_limit,
+            reason = EXCLUDED.reason, created_at = CURRENT_TIMESTAMP
+        RETURNING *
+        "#,
+        occurrence_id,
+        claims.user_id,
+        payload.cancelled,
+        payload.start_time,
+        payload.end_time,
+        payload.location,
+        payload.user_limit,
+        payload.reason
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let occurrence = occurrences::fetch_occurrence(&mut tx, id, occurrence_id).await?;
+    let released = release_bookings(&mut tx, &occurrence).await?;
+    let promoted = bookings::promote_waitlist(&mut tx, &occurrence).await?;
+    tx.commit().await?;
+
+    Ok(Json(ExceptionOutcome {
+        exception,
+        occurrence,
+        released,
+        promoted,
+    }))
+}
+
+/// Removes an exception so the occurrence follows the series again
+async fn delete_exception(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path((id, occurrence_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<ExceptionOutcome>> {
+    let mut tx = pool.begin().await?;
+    sessions::authorize_edit(&mut tx, &claims, id).await?;
+    sqlx::query!(
+        "SELECT id FROM records.session_occurrences WHERE id = $1 AND form_id = $2 FOR UPDATE",
+        occurrence_id,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::NotFound("Occurrence not found".into()))?;
+
+    let exception = sqlx::query_as!(
+        OccurrenceException,
+        "DELETE FROM records.occurrence_exceptions WHERE occurrence_id = $1 RETURNING *",
+        occurrence_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::NotFound("Exception not found".into()))?;
+
+    // the series limit may be lower or higher than the one the exception set
+    let occurrence = occurrences::fetch_occurrence(&mut tx, id, occurrence_id).await?;
+    let released = release_bookings(&mut tx, &occurrence).await?;
+    let promoted = bookings::promote_waitlist(&mut tx, &occurrence).await?;
+    tx.commit().await?;
+
+    Ok(Json(ExceptionOutcome {
+        exception,
+        occurrence,
+        released,
+        promoted,
+    }))
+}
@@ -0,0 +1,196 @@
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use tracing::error;
+
+use crate::http::api_keys::{ApiKeyScopes, Scope};
+use crate::http::roles::Permission;
+use crate::http::token::{AccessClaims, AuthError};
+use crate::{Error, Result};
+
+/// The claims of the logged-in user, as put in the request extensions by `mid_jwt_auth`. Only
+/// usable on routes behind that middleware; elsewhere the request is rejected as unauthenticated.
+#[derive(Debug, Clone)]
+pub struct CurrentUser(pub AccessClaims);
+
+/// A logged-in admin
+#[derive(Debug, Clone)]
+pub struct RequireAdmin(pub AccessClaims);
+
+/// A logged-in user whose tier is at least `N` (1 = member, 2 = team)
+#[derive(Debug, Clone)]
+pub struct RequireTier<const N: i16>(pub AccessClaims);
+
+/// A logged-in user holding the permission `P` through one of their roles. Admins hold
+/// every permission.
+#[derive(Debug, Clone)]
+pub struct RequirePermission<P>(pub AccessClaims, pub PhantomData<fn() -> P>);
+
+/// A user logged in with a token, or an API key granted the scope `S`. This is the only
+/// extractor that lets API keys through.
+#[derive(Debug, Clone)]
+pub struct RequireScope<S>(pub AccessClaims, pub PhantomData<fn() -> S>);
+
+/// The address of the client that made the request, when the server was started with connect
+/// info. Behind a proxy listed in `TRUSTED_PROXIES` this is taken from `X-Forwarded-For`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// Comma separated addresses or networks, e.g. `127.0.0.1,10.0.0.0/8`
+static TRUSTED_PROXIES: Lazy<Vec<IpNet>> = Lazy::new(|| {
+    dotenvy::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .expect("TRUSTED_PROXIES must be a list of addresses or networks")
+        })
+        .collect()
+});
+
+fn is_trusted(ip: &IpAddr) -> bool {
+    TRUSTED_PROXIES.iter().any(|net| net.contains(ip))
+}
+
+/// Walks `X-Forwarded-For` from the right while the hop is one of our proxies. The first
+/// untrusted hop is the client; anything to its left could have been made up by them.
+fn forwarded_client(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let mut client = peer;
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        if !is_trusted(&client) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+fn claims(parts: &Parts) -> Result<AccessClaims> {
+    if parts.extensions.get::<ApiKeyScopes>().is_some() {
+        error!(name: "exception_error", "API key used on {}, which takes no scope", parts.uri.path());
+        return Err(AuthError::ApiKeyNotAllowed.into());
+    }
+    token_claims(parts)
+}
+
+fn token_claims(parts: &Parts) -> Result<AccessClaims> {
+    parts
+        .extensions
+        .get::<AccessClaims>()
+        .cloned()
+        .ok_or_else(|| {
+            error!(name: "exception_error", "Access claims missing from request extensions");
+            Error::from(AuthError::MissingCredentials)
+        })
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        Ok(CurrentUser(claims(parts)?))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequireAdmin {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        let claims = claims(parts)?;
+        if !claims.admin {
+            error!(name: "exception_error", "Non-admin {} tried to use {}", claims.user_id, parts.uri.path());
+            return Err(AuthError::NotAdmin.into());
+        }
+        if !claims.is_admin() {
+            error!(name: "exception_error", "Admin {} without 2FA tried to use {}", claims.user_id, parts.uri.path());
+            return Err(AuthError::MfaRequired.into());
+        }
+        Ok(RequireAdmin(claims))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync, const N: i16> FromRequestParts<S> for RequireTier<N> {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        let claims = claims(parts)?;
+        if claims.tier < N {
+            error!(name: "exception_error", "User {} of tier {} tried to use {}", claims.user_id, claims.tier, parts.uri.path());
+            return Err(AuthError::InsufficientTier { required: N }.into());
+        }
+        Ok(RequireTier(claims))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync, P: Permission> FromRequestParts<S> for RequirePermission<P> {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        let claims = claims(parts)?;
+        if !claims.has_permission(P::NAME) {
+            error!(name: "exception_error", "User {} without {} tried to use {}", claims.user_id, P::NAME, parts.uri.path());
+            return Err(AuthError::MissingPermission {
+                permission: P::NAME,
+            }
+            .into());
+        }
+        Ok(RequirePermission(claims, PhantomData))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync, T: Scope> FromRequestParts<S> for RequireScope<T> {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        let claims = token_claims(parts)?;
+        if let Some(ApiKeyScopes(scopes)) = parts.extensions.get::<ApiKeyScopes>() {
+            if !scopes.iter().any(|scope| scope == T::NAME) {
+                error!(name: "exception_error", "API key of {} without {} used on {}", claims.user_id, T::NAME, parts.uri.path());
+                return Err(AuthError::MissingScope { scope: T::NAME }.into());
+            }
+        }
+        Ok(RequireScope(claims, PhantomData))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Infallible> {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| forwarded_client(addr.ip(), &parts.headers));
+        Ok(ClientIp(ip))
+    }
+}
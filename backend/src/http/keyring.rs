@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    errors::{ErrorKind, Result as JwtResult},
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+};
+use ring::{
+    rsa::PublicKeyComponents,
+    signature::{Ed25519KeyPair, KeyPair, RsaKeyPair},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Kid of the key read from `<PREFIX>_SECRET`, also used for tokens issued before tokens
+/// carried a kid
+const DEFAULT_KID: &str = "default";
+
+struct SigningKey {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Option<Jwk>, // public half, for asymmetric keys only
+}
+
+/// Every key a kind of token may be signed with, by kid. New tokens are signed with the
+/// current key, and a token is accepted as long as the key named in its header is still
+/// configured, so keys can be rotated without logging anyone out.
+///
+/// Keys are read from `<PREFIX>_KEYS_DIR`, one file per key named after its kid: `<kid>.secret`
+/// for an HS256 secret, `<kid>.pem` for an Ed25519 or RSA private key. `<PREFIX>_SECRET` is
+/// still read as the HS256 key `default`. `<PREFIX>_CURRENT_KID` picks the signing key and
+/// defaults to the last kid in sorted order, so dated kids rotate by adding a file.
+pub struct Keyring {
+    current: String,
+    keys: BTreeMap<String, SigningKey>,
+}
+
+impl Keyring {
+    pub fn from_env(prefix: &str) -> Keyring {
+        let mut keys = BTreeMap::new();
+        if let Ok(secret) = dotenvy::var(format!("{prefix}_SECRET")) {
+            keys.insert(DEFAULT_KID.to_string(), hmac_key(secret.as_bytes()));
+        }
+        if let Ok(dir) = dotenvy::var(format!("{prefix}_KEYS_DIR")) {
+            let entries = std::fs::read_dir(&dir)
+                .unwrap_or_else(|e| panic!("Cannot read {prefix}_KEYS_DIR {dir}: {e}"));
+            for entry in entries {
+                let path = entry.expect("readable key directory entry").path();
+                if let Some((kid, key)) = read_key(&path) {
+                    keys.insert(kid, key);
+                }
+            }
+        }
+
+        let current = dotenvy::var(format!("{prefix}_CURRENT_KID"))
+            .ok()
+            .or_else(|| keys.keys().rev().find(|kid| *kid != DEFAULT_KID).cloned())
+            .unwrap_or_else(|| DEFAULT_KID.to_string());
+        assert!(
+            keys.contains_key(&current),
+            "{prefix}_SECRET or {prefix}_KEYS_DIR must provide the signing key {current}"
+        );
+        Keyring { current, keys }
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> JwtResult<String> {
+        let key = &self.keys[&self.current];
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(self.current.clone());
+        jsonwebtoken::encode(&header, claims, &key.encoding)
+    }
+
+    /// Verifies with the key named by the token's kid, only accepting that key's algorithm
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> JwtResult<TokenData<T>> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let kid = header.kid.as_deref().unwrap_or(DEFAULT_KID);
+        let key = self.keys.get(kid).ok_or(ErrorKind::InvalidToken)?;
+        jsonwebtoken::decode(token, &key.decoding, &Validation::new(key.algorithm))
+    }
+
+    /// Public keys for other services to verify our tokens with. HMAC secrets are left out.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .values()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+fn hmac_key(secret: &[u8]) -> SigningKey {
+    SigningKey {
+        algorithm: Algorithm::HS256,
+        encoding: EncodingKey::from_secret(secret),
+        decoding: DecodingKey::from_secret(secret),
+        jwk: None,
+    }
+}
+
+fn read_key(path: &Path) -> Option<(String, SigningKey)> {
+    let kid = path.file_stem()?.to_str()?.to_string();
+    let contents =
+        std::fs::read(path).unwrap_or_else(|e| panic!("Cannot read key {}: {e}", path.display()));
+    let key = match path.extension()?.to_str()? {
+        "secret" => hmac_key(contents.trim_ascii()),
+        "pem" => asymmetric_key(&kid, &contents)
+            .unwrap_or_else(|| panic!("{} is not an Ed25519 or RSA private key", path.display())),
+        _ => return None,
+    };
+    Some((kid, key))
+}
+
+fn asymmetric_key(kid: &str, pem: &[u8]) -> Option<SigningKey> {
+    let parsed = pem::parse(pem).ok()?;
+    let der = parsed.contents();
+    let common = |algorithm| CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(algorithm),
+        key_id: Some(kid.to_string()),
+        ..Default::default()
+    };
+
+    if parsed.tag() == "PRIVATE KEY" {
+        if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+            let x = URL_SAFE_NO_PAD.encode(pair.public_key().as_ref());
+            return Some(SigningKey {
+                algorithm: Algorithm::EdDSA,
+                encoding: EncodingKey::from_ed_pem(pem).ok()?,
+                decoding: DecodingKey::from_ed_components(&x).ok()?,
+                jwk: Some(Jwk {
+                    common: common(KeyAlgorithm::EdDSA),
+                    algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x,
+                    }),
+                }),
+            });
+        }
+    }
+
+    let pair = match parsed.tag() {
+        "PRIVATE KEY" => RsaKeyPair::from_pkcs8(der).ok()?,
+        "RSA PRIVATE KEY" => RsaKeyPair::from_der(der).ok()?,
+        _ => return None,
+    };
+    let public = PublicKeyComponents::<Vec<u8>>::from(pair.public());
+    let n = URL_SAFE_NO_PAD.encode(&public.n);
+    let e = URL_SAFE_NO_PAD.encode(&public.e);
+    Some(SigningKey {
+        algorithm: Algorithm::RS256,
+        encoding: EncodingKey::from_rsa_pem(pem).ok()?,
+        decoding: DecodingKey::from_rsa_components(&n, &e).ok()?,
+        jwk: Some(Jwk {
+            common: common(KeyAlgorithm::RS256),
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n,
+                e,
+            }),
+        }),
+    })
+}
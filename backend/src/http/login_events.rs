@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Json, Query, State},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::http::{AppState, RequireAdmin};
+use crate::Result;
+
+/// Most events returned by one request to the history endpoint
+const MAX_EVENTS: i64 = 500;
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct LoginEvent {
+    pub id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub shortcode: String,
+    pub kind: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub success: bool,
+    pub failure_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A login or refresh attempt to be written to the audit trail
+#[derive(Debug)]
+pub struct Attempt<'a> {
+    pub user_id: Option<Uuid>,
+    pub shortcode: &'a str,
+    pub kind: &'static str,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<&'a str>,
+    pub failure_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginEventFilter {
+    pub user_id: Option<Uuid>,
+    pub shortcode: Option<String>,
+    pub ip: Option<String>,
+    pub success: Option<bool>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new().route("/login-events", get(list_login_events))
+}
+
+/// Writes an attempt to the audit trail. Successful attempts also become the user's
+/// `last_login`.
+pub async fn record(pool: &sqlx::PgPool, attempt: Attempt<'_>) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.login_events(user_id, shortcode, kind, ip, user_agent, success, failure_reason)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        attempt.user_id,
+        attempt.shortcode,
+        attempt.kind,
+        attempt.ip.map(|ip| ip.to_string()),
+        attempt.user_agent,
+        attempt.failure_reason.is_none(),
+        attempt.failure_reason
+    )
+    .execute(pool)
+    .await?;
+
+    if let (Some(user_id), None) = (attempt.user_id, attempt.failure_reason) {
+        sqlx::query!(
+            "UPDATE auth.users SET last_login = CURRENT_TIMESTAMP WHERE id = $1",
+            user_id
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Login history, newest first, for looking into shared or compromised accounts
+async fn list_login_events(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Query(filter): Query<LoginEventFilter>,
+) -> Result<Json<Vec<LoginEvent>>> {
+    let events = sqlx::query_as!(
+        LoginEvent,
+        r#"
+        SELECT * FROM auth.login_events
+        WHERE ($1::uuid IS NULL OR user_id = $1)
+            AND ($2::text IS NULL OR shortcode = $2)
+            AND ($3::text IS NULL OR ip = $3)
+            AND ($4::bool IS NULL OR success = $4)
+            AND ($5::timestamptz IS NULL OR created_at >= $5)
+            AND ($6::timestamptz IS NULL OR created_at <= $6)
+        ORDER BY created_at DESC
+        LIMIT $7
+        "#,
+        filter.user_id,
+        filter.shortcode,
+        filter.ip,
+        filter.success,
+        filter.from,
+        filter.to,
+        filter.limit.unwrap_or(MAX_EVENTS).clamp(1, MAX_EVENTS)
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(events))
+}
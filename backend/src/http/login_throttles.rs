@@ -0,0 +1,222 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    routing::{delete, get},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::http::{AppState, RequireAdmin};
+use crate::{Error, Result};
+
+/// Counters start again after this long without a failure
+const FAILURE_WINDOW_SECONDS: f64 = 3600.0;
+/// Delay after the first failure past the free attempts, doubled for each one after
+const BACKOFF_BASE_SECONDS: i64 = 1;
+const BACKOFF_MAX_SECONDS: i64 = 300;
+const LOCKOUT_SECONDS: i64 = 900;
+
+struct Policy {
+    scope: &'static str,
+    free_attempts: i32,
+    lockout_after: i32,
+}
+
+const ACCOUNT: Policy = Policy {
+    scope: "shortcode",
+    free_attempts: 3,
+    lockout_after: 10,
+};
+
+// Looser than the account policy as several people can share an address, e.g. on eduroam
+const ADDRESS: Policy = Policy {
+    scope: "ip",
+    free_attempts: 10,
+    lockout_after: 50,
+};
+
+impl Policy {
+    /// How long to refuse further attempts after the given number of failures
+    fn delay(&self, failures: i32) -> Option<i64> {
+        if failures >= self.lockout_after {
+            Some(LOCKOUT_SECONDS)
+        } else if failures > self.free_attempts {
+            let doublings = (failures - self.free_attempts - 1).min(16) as u32;
+            Some((BACKOFF_BASE_SECONDS << doublings).min(BACKOFF_MAX_SECONDS))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct LoginThrottle {
+    pub scope: String,
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/login-throttles", get(list_throttles))
+        .route("/login-throttles/ip/:ip", delete(unlock_ip))
+        .route("/users/:id/lockout", delete(unlock_user))
+}
+
+/// Rejects the attempt with the remaining wait if the shortcode or address is locked
+pub async fn check(pool: &sqlx::PgPool, shortcode: &str, ip: Option<IpAddr>) -> Result<()> {
+    let locked_until = sqlx::query_scalar!(
+        r#"
+        SELECT MAX(locked_until) FROM auth.login_throttles
+        WHERE locked_until > CURRENT_TIMESTAMP
+            AND ((scope = 'shortcode' AND key = $1) OR (scope = 'ip' AND key = $2))
+        "#,
+        shortcode,
+        ip.map(|ip| ip.to_string())
+    )
+    .fetch_one(pool)
+    .await?;
+
+    match locked_until {
+        Some(until) => {
+            let wait = (until - Utc::now()).num_seconds().max(1);
+            Err(Error::TooManyRequests(wait as u64))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Counts a failed attempt against the shortcode and the address it came from
+pub async fn record_failure(
+    pool: &sqlx::PgPool,
+    shortcode: &str,
+    ip: Option<IpAddr>,
+) -> Result<()> {
+    bump(pool, &ACCOUNT, shortcode).await?;
+    if let Some(ip) = ip {
+        bump(pool, &ADDRESS, &ip.to_string()).await?;
+    }
+    Ok(())
+}
+
+/// Forgets earlier failures on the shortcode once its owner gets in. Address counters are left
+/// alone so an attacker can't reset them by logging into their own account.
+pub async fn clear(pool: &sqlx::PgPool, shortcode: &str) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM auth.login_throttles WHERE scope = 'shortcode' AND key = $1",
+        shortcode
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Deletes counters that are no longer locked and have gone a whole failure window without a
+/// failure, as they would start again from one anyway
+pub async fn purge_login_throttles(pool: &sqlx::PgPool) -> Result<u64> {
+    let purged = sqlx::query!(
+        r#"
+        DELETE FROM auth.login_throttles
+        WHERE last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+            AND (locked_until IS NULL OR locked_until <= CURRENT_TIMESTAMP)
+        "#,
+        FAILURE_WINDOW_SECONDS
+    )
+    .execute(pool)
+    .await?;
+    if purged.rows_affected() > 0 {
+        info!("Purged {} idle login throttles", purged.rows_affected());
+    }
+    Ok(purged.rows_affected())
+}
+
+async fn bump(pool: &sqlx::PgPool, policy: &Policy, key: &str) -> Result<()> {
+    let failures = sqlx::query_scalar!(
+        r#"
+        INSERT INTO auth.login_throttles(scope, key, failures)
+        VALUES ($1, $2, 1)
+        ON CONFLICT (scope, key) DO UPDATE SET
+            failures = CASE
+                WHEN login_throttles.last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $3)
+                THEN 1
+                ELSE login_throttles.failures + 1
+            END,
+            last_failure_at = CURRENT_TIMESTAMP
+        RETURNING failures
+        "#,
+        policy.scope,
+        key,
+        FAILURE_WINDOW_SECONDS
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if let Some(delay) = policy.delay(failures) {
+        sqlx::query!(
+            r#"
+            UPDATE auth.login_throttles
+            SET locked_until = CURRENT_TIMESTAMP + make_interval(secs => $3)
+            WHERE scope = $1 AND key = $2
+            "#,
+            policy.scope,
+            key,
+            delay as f64
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn list_throttles(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+) -> Result<Json<Vec<LoginThrottle>>> {
+    let throttles = sqlx::query_as!(
+        LoginThrottle,
+        "SELECT * FROM auth.login_throttles ORDER BY last_failure_at DESC"
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(throttles))
+}
+
+async fn unlock_user(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let shortcode = sqlx::query_scalar!("SELECT shortcode FROM auth.users WHERE id = $1", id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| Error::NotFound("User not found".into()))?;
+    clear(&pool, &shortcode).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unlock_ip(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Path(ip): Path<IpAddr>,
+) -> Result<StatusCode> {
+    let deleted = sqlx::query!(
+        "DELETE FROM auth.login_throttles WHERE scope = 'ip' AND key = $1",
+        ip.to_string()
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(Error::NotFound("No throttle for this address".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,468 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Json, State},
+    http::{header::USER_AGENT, HeaderMap, StatusCode},
+    response::Response,
+    routing::post,
+    Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::http::cookies::Transport;
+use crate::http::login_events::{self, Attempt};
+use crate::http::login_throttles;
+use crate::http::token::{self, AccessClaims, AuthError};
+use crate::http::{AppState, ClientIp, CurrentUser};
+use crate::{Error, Result};
+
+/// Name shown next to the account in authenticator apps
+const ISSUER: &str = "ICSM";
+const TOTP_STEP_SECONDS: i64 = 30;
+/// How long the second login step can be completed for after the password was accepted
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Lowercase letters and digits without the easily confused 0, o, 1, i and l
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// When set, admin rights need a login that passed two-factor authentication
+pub static REQUIRE_ADMIN_2FA: Lazy<bool> = Lazy::new(|| {
+    dotenvy::var("REQUIRE_ADMIN_2FA").is_ok_and(|value| value == "true" || value == "1")
+});
+
+#[derive(Debug, Serialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub challenge_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Enrolment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// A code from an authenticator app or one of the recovery codes
+#[derive(Debug, Deserialize)]
+pub struct CodePayload {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecondStepPayload {
+    pub challenge_id: Uuid,
+    pub code: String,
+    #[serde(default)]
+    pub transport: Transport,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/login/2fa", post(complete_login))
+}
+
+pub fn me_router() -> Router<AppState> {
+    Router::new()
+        .route("/2fa", post(enroll).delete(disable))
+        .route("/2fa/confirm", post(confirm))
+        .route("/2fa/recovery-codes", post(regenerate_recovery_codes))
+}
+
+fn totp(secret: &str, shortcode: &str) -> Result<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| {
+            error!(name: "mfa_error", "Stored TOTP secret is not valid base32: {:?}", e);
+            AuthError::InvalidToken
+        })?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        TOTP_STEP_SECONDS as u64,
+        bytes,
+        Some(ISSUER.to_string()),
+        shortcode.to_string(),
+    )
+    .map_err(|e| {
+        error!(name: "mfa_error", "Cannot build TOTP from stored secret: {:?}", e);
+        Error::from(AuthError::InvalidToken)
+    })
+}
+
+/// Recovery codes are compared without case or separators, so only that form is hashed
+fn hash_recovery_code(code: &str) -> String {
+    let normalised = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    hex::encode(Sha256::digest(normalised.as_bytes()))
+}
+
+fn new_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code = String::with_capacity(11);
+    for i in 0..10 {
+        if i == 5 {
+            code.push('-');
+        }
+        let index = rng.gen_range(0..RECOVERY_CODE_ALPHABET.len());
+        code.push(RECOVERY_CODE_ALPHABET[index] as char);
+    }
+    code
+}
+
+pub async fn is_enabled(pool: &sqlx::PgPool, user_id: Uuid) -> Result<bool> {
+    let enabled = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM auth.user_totp WHERE user_id = $1 AND enabled_at IS NOT NULL) AS "enabled!""#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(enabled)
+}
+
+/// Starts the second login step for a user whose password has been accepted
+pub async fn create_challenge(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    keep_login: bool,
+    device_label: Option<String>,
+) -> Result<MfaChallenge> {
+    sqlx::query!(
+        "DELETE FROM auth.mfa_challenges WHERE user_id = $1 AND expires_at < CURRENT_TIMESTAMP",
+        user_id
+    )
+    .execute(pool)
+    .await?;
+
+    let expires_at = Utc::now() + Duration::minutes(CHALLENGE_TTL_MINUTES);
+    let challenge_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO auth.mfa_challenges(user_id, keep_login, device_label, expires_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id
+        "#,
+        user_id,
+        keep_login,
+        device_label,
+        expires_at
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(MfaChallenge {
+        mfa_required: true,
+        challenge_id,
+        expires_at,
+    })
+}
+
+/// Checks a code against the user's enabled authenticator, falling back to their unused
+/// recovery codes. A code is accepted at most once.
+async fn verify_code(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    shortcode: &str,
+    code: &str,
+) -> Result<bool> {
+    let stored = sqlx::query!(
+        "SELECT secret, last_used_step FROM auth.user_totp WHERE user_id = $1 AND enabled_at IS NOT NULL",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(stored) = stored else {
+        return Ok(false);
+    };
+
+    if let Some(step) = matching_step(
+        &totp(&stored.secret, shortcode)?,
+        code,
+        stored.last_used_step,
+    ) {
+        return consume_step(pool, user_id, step).await;
+    }
+
+    let used = sqlx::query!(
+        r#"
+        UPDATE auth.recovery_codes SET used_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+        user_id,
+        hash_recovery_code(code)
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if used > 0 {
+        info!("User {} logged in with a recovery code", user_id);
+    }
+    Ok(used > 0)
+}
+
+/// The time step `code` was generated for, allowing one step of clock drift either way.
+/// Steps at or before the last one used are refused so a code can't be replayed.
+fn matching_step(totp: &TOTP, code: &str, last_used_step: i64) -> Option<i64> {
+    let current = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+    (current - 1..=current + 1)
+        .filter(|&step| step > last_used_step)
+        .find(|&step| totp.generate((step * TOTP_STEP_SECONDS) as u64) == code.trim())
+}
+
+async fn consume_step(pool: &sqlx::PgPool, user_id: Uuid, step: i64) -> Result<bool> {
+    let updated = sqlx::query!(
+        "UPDATE auth.user_totp SET last_used_step = $2 WHERE user_id = $1 AND last_used_step < $2",
+        user_id,
+        step
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(updated > 0)
+}
+
+async fn replace_recovery_codes(pool: &sqlx::PgPool, user_id: Uuid) -> Result<Vec<String>> {
+    let codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| new_recovery_code())
+        .collect::<Vec<_>>();
+    let hashes = codes
+        .iter()
+        .map(|code| hash_recovery_code(code))
+        .collect::<Vec<_>>();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM auth.recovery_codes WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO auth.recovery_codes(user_id, code_hash) SELECT $1, UNNEST($2::text[])",
+        user_id,
+        &hashes
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(codes)
+}
+
+/// Second login step, swapping a challenge and a code for the usual tokens
+#[instrument(level = "trace", skip(pool, headers, payload))]
+async fn complete_login(
+    State(pool): State<sqlx::PgPool>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<SecondStepPayload>,
+) -> Result<Response> {
+    // counting the attempt up front keeps concurrent guesses within the limit
+    let challenge = sqlx::query!(
+        r#"
+        UPDATE auth.mfa_challenges SET attempts = attempts + 1
+        WHERE id = $1 AND expires_at > CURRENT_TIMESTAMP AND attempts < $2
+        RETURNING user_id, keep_login, device_label
+        "#,
+        payload.challenge_id,
+        MAX_CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| {
+        error!(name: "exception_error", "Second login step for unknown or used up challenge");
+        AuthError::InvalidToken
+    })?;
+
+    let user = sqlx::query_as!(
+        crate::http::User,
+        "SELECT * FROM auth.users WHERE id = $1",
+        challenge.user_id
+    )
+    .fetch_one(&pool)
+    .await?;
+    login_throttles::check(&pool, &user.shortcode, ip).await?;
+
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    let mut attempt = Attempt {
+        user_id: Some(user.id),
+        shortcode: &user.shortcode,
+        kind: "login",
+        ip,
+        user_agent,
+        failure_reason: None,
+    };
+
+    if !verify_code(&pool, user.id, &user.shortcode, &payload.code).await? {
+        attempt.failure_reason = Some("wrong_2fa_code");
+        login_events::record(&pool, attempt).await?;
+        login_throttles::record_failure(&pool, &user.shortcode, ip).await?;
+        return Err(Error::from(AuthError::WrongCredentials));
+    }
+
+    sqlx::query!(
+        "DELETE FROM auth.mfa_challenges WHERE id = $1",
+        payload.challenge_id
+    )
+    .execute(&pool)
+    .await?;
+    login_events::record(&pool, attempt).await?;
+    login_throttles::clear(&pool, &user.shortcode).await?;
+    token::issue_tokens(
+        &pool,
+        &user,
+        challenge.keep_login,
+        challenge.device_label,
+        user_agent,
+        true,
+        payload.transport,
+    )
+    .await
+}
+
+/// Generates a new secret, which only takes effect once confirmed with a code from it
+async fn enroll(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+) -> Result<(StatusCode, Json<Enrolment>)> {
+    if is_enabled(&pool, claims.user_id).await? {
+        return Err(Error::Conflict(
+            "Two-factor authentication is already enabled".into(),
+        ));
+    }
+
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.user_totp(user_id, secret)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET secret = $2, last_used_step = 0, created_at = CURRENT_TIMESTAMP
+        "#,
+        claims.user_id,
+        secret
+    )
+    .execute(&pool)
+    .await?;
+
+    let otpauth_uri = totp(&secret, &claims.sub)?.get_url();
+    Ok((
+        StatusCode::CREATED,
+        Json(Enrolment {
+            secret,
+            otpauth_uri,
+        }),
+    ))
+}
+
+/// Turns on two-factor authentication and hands out the recovery codes, which are not shown again
+async fn confirm(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Json(payload): Json<CodePayload>,
+) -> Result<Json<RecoveryCodes>> {
+    let pending = sqlx::query!(
+        "SELECT secret, last_used_step FROM auth.user_totp WHERE user_id = $1 AND enabled_at IS NULL",
+        claims.user_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("No two-factor enrolment waiting to be confirmed".into()))?;
+
+    let step = matching_step(
+        &totp(&pending.secret, &claims.sub)?,
+        &payload.code,
+        pending.last_used_step,
+    )
+    .ok_or(AuthError::WrongCredentials)?;
+    sqlx::query!(
+        r#"
+        UPDATE auth.user_totp SET enabled_at = CURRENT_TIMESTAMP, last_used_step = $2
+        WHERE user_id = $1 AND enabled_at IS NULL
+        "#,
+        claims.user_id,
+        step
+    )
+    .execute(&pool)
+    .await?;
+
+    let recovery_codes = replace_recovery_codes(&pool, claims.user_id).await?;
+    info!("User {} enabled two-factor authentication", claims.user_id);
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+/// Checks the code a logged-in user gave to change their two-factor settings. Wrong codes count
+/// against the same throttles as logins, so a stolen session can't be used to guess them.
+async fn verify_own_code(
+    pool: &sqlx::PgPool,
+    claims: &AccessClaims,
+    ip: Option<IpAddr>,
+    code: &str,
+) -> Result<()> {
+    login_throttles::check(pool, &claims.sub, ip).await?;
+    if !verify_code(pool, claims.user_id, &claims.sub, code).await? {
+        login_throttles::record_failure(pool, &claims.sub, ip).await?;
+        return Err(Error::from(AuthError::WrongCredentials));
+    }
+    Ok(())
+}
+
+async fn regenerate_recovery_codes(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<CodePayload>,
+) -> Result<Json<RecoveryCodes>> {
+    verify_own_code(&pool, &claims, ip, &payload.code).await?;
+    let recovery_codes = replace_recovery_codes(&pool, claims.user_id).await?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+async fn disable(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<CodePayload>,
+) -> Result<StatusCode> {
+    if !is_enabled(&pool, claims.user_id).await? {
+        return Err(Error::NotFound(
+            "Two-factor authentication is not enabled".into(),
+        ));
+    }
+    verify_own_code(&pool, &claims, ip, &payload.code).await?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM auth.user_totp WHERE user_id = $1",
+        claims.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM auth.recovery_codes WHERE user_id = $1",
+        claims.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!("User {} disabled two-factor authentication", claims.user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
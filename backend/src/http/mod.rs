@@ -1,26 +1,144 @@
-use axum::{middleware::from_fn, Router};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
+use axum::{
+    extract::FromRef,
+    middleware::from_fn_with_state,
+    Router,
+};
+
+mod api_keys;
+mod ballots;
+mod bookings;
+mod cookies;
 mod defaults;
+mod exceptions;
+mod extractors;
+mod keyring;
+mod login_events;
+mod login_throttles;
+mod mfa;
+mod occurrences;
+mod oidc;
+mod outbox;
+mod password_reset;
 mod pg_interval;
+mod rate_limit;
+mod roles;
 mod sessions;
 mod token;
 mod users;
 
+pub use self::api_keys::{BookingsRead, BookingsWrite, Scope, SessionBookingsRead, SessionsRead};
+pub use self::ballots::draw_due_ballots;
+pub use self::bookings::BookingError;
+pub use self::extractors::{
+    ClientIp, CurrentUser, RequireAdmin, RequirePermission, RequireScope, RequireTier,
+};
+pub use self::login_throttles::purge_login_throttles;
+pub use self::roles::{ManageSessions, Permission, ViewMembers, ViewPayments};
 pub use self::token::AuthError;
-pub use self::users::{get_members, User};
+pub use self::users::{get_members, purge_pending_users, User};
+use self::rate_limit::{Limit, RateLimiter};
+use crate::mail::Mailer;
 use crate::Result;
 
-pub fn router_app(db: sqlx::PgPool) -> Router {
-    let user_router = Router::new().merge(token::router()).merge(users::router());
+#[derive(Clone)]
+pub struct AppState {
+    pub db: sqlx::PgPool,
+    pub mailer: Arc<dyn Mailer>,
+}
+
+impl FromRef<AppState> for sqlx::PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Mailer> {
+    fn from_ref(state: &AppState) -> Self {
+        state.mailer.clone()
+    }
+}
+
+pub fn router_app(state: AppState) -> Router {
+    // Each group of routes gets its own buckets, set as RATE_LIMIT_<GROUP>=burst/seconds
+    let login_limiter = RateLimiter::new(Limit::from_env(
+        "RATE_LIMIT_LOGIN",
+        Limit { burst: 10, period_seconds: 60 },
+    ));
+    let login_step_limiter = RateLimiter::new(Limit::from_env(
+        "RATE_LIMIT_LOGIN_STEP",
+        Limit { burst: 30, period_seconds: 60 },
+    ));
+    let refresh_limiter = RateLimiter::new(Limit::from_env(
+        "RATE_LIMIT_REFRESH",
+        Limit { burst: 10, period_seconds: 60 },
+    ));
+    let signup_limiter = RateLimiter::new(Limit::from_env(
+        "RATE_LIMIT_SIGNUP",
+        Limit { burst: 20, period_seconds: 300 },
+    ));
+    let api_limiter = RateLimiter::new(Limit::from_env(
+        "RATE_LIMIT_API",
+        Limit { burst: 120, period_seconds: 60 },
+    ));
+
+    // only password logins share the strict per address limit, the later steps of a login and
+    // refreshes get their own
+    let login_router = Router::new()
+        .merge(token::router())
+        .layer(from_fn_with_state(login_limiter, rate_limit::limit));
+    let login_step_router = Router::new()
+        .merge(mfa::router())
+        .merge(oidc::router())
+        .layer(from_fn_with_state(login_step_limiter, rate_limit::limit));
+    let refresh_router = token::refresh_router()
+        .layer(from_fn_with_state(refresh_limiter, rate_limit::limit_refresh));
+    let signup_router = Router::new()
+        .merge(users::router())
+        .nest("/password", password_reset::router())
+        .layer(from_fn_with_state(signup_limiter, rate_limit::limit));
+    let user_router = Router::new()
+        .merge(login_router)
+        .merge(login_step_router)
+        .merge(refresh_router)
+        .merge(signup_router);
+    let me_router = Router::new()
+        .merge(users::me_router())
+        .merge(token::me_router())
+        .merge(mfa::me_router())
+        .merge(bookings::me_router());
+    let protected_user_router = Router::new()
+        .merge(token::protected_router())
+        .nest("/me", me_router);
+    let admin_router = Router::new()
+        .merge(token::admin_router())
+        .merge(outbox::admin_router())
+        .merge(roles::admin_router())
+        .merge(login_events::admin_router())
+        .merge(login_throttles::admin_router())
+        .merge(api_keys::admin_router());
     let v1_routes = Router::new()
         .nest("/sessions", sessions::router())
-        .layer(from_fn(token::mid_jwt_auth)) // all routes above are protected
+        .nest("/users", protected_user_router)
+        .nest("/members", users::members_router())
+        .nest("/admin", admin_router)
+        .layer(from_fn_with_state(api_limiter, rate_limit::limit))
+        .layer(from_fn_with_state(state.clone(), token::mid_jwt_auth)) // all routes above are protected
         .nest("/users", user_router);
-    Router::new().nest("/api/v1", v1_routes).with_state(db)
+    Router::new()
+        .merge(token::jwks_router())
+        .nest("/api/v1", v1_routes)
+        .with_state(state)
 }
 
-pub async fn serve(db: sqlx::PgPool) -> Result<()> {
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
-    axum::serve(listener, router_app(db)).await;
+pub async fn serve(state: AppState) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await?;
+    axum::serve(
+        listener,
+        router_app(state).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
@@ -0,0 +1,378 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Days, LocalResult, Months, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::{Europe::London, Tz};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::http::sessions::SessionForm;
+use crate::http::{AppState, RequireScope, SessionsRead};
+use crate::{Error, Result};
+
+/// Sessions are scheduled in UK local time, so a 19:00 club night stays at 19:00 across BST
+const SCHEDULE_TZ: Tz = London;
+
+/// Upper bound on how many occurrences a single series can expand to
+const MAX_OCCURRENCES: u32 = 520;
+
+#[derive(sqlx::FromRow, Debug, Deserialize, Serialize)]
+pub struct Occurrence {
+    pub id: Uuid,
+    pub form_id: Uuid,
+    pub seq: i32,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub location: String,
+    pub user_limit: Option<i16>,
+    pub tier: i16,
+    pub cancelled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OccurrenceFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/occurrences", get(list_occurrences))
+        .route("/:id/occurrences", get(list_session_occurrences))
+}
+
+/// Adds `n` recurrence steps to a local wall-clock time. Steps are applied from the series
+/// start rather than cumulatively, so a monthly session on the 31st does not drift to the 28th.
+fn step(local: NaiveDateTime, form: &SessionForm, n: u32) -> Option<NaiveDateTime> {
+    let interval = form.recurrence.as_ref()?;
+    let months = u32::try_from(interval.months).ok()?.checked_mul(n)?;
+    let days = u64::try_from(interval.days).ok()?.checked_mul(n as u64)?;
+    let microseconds = interval.microseconds.checked_mul(n as i64)?;
+
+    local
+        .checked_add_months(Months::new(months))?
+        .checked_add_days(Days::new(days))?
+        .checked_add_signed(chrono::Duration::microseconds(microseconds))
+}
+
+/// Resolves a local wall-clock time to UTC. Ambiguous times (clocks going back) take the
+/// earlier instant and non-existent times (clocks going forward) are pushed past the gap.
+fn to_utc(local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    match SCHEDULE_TZ.from_local_datetime(&local) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.with_timezone(&Utc)),
+        LocalResult::None => to_utc(local.checked_add_signed(chrono::Duration::hours(1))?),
+    }
+}
+
+/// Fetches an occurrence of a series with its exception, if any, applied
+pub async fn fetch_occurrence(
+    conn: &mut sqlx::PgConnection,
+    form_id: Uuid,
+    id: Uuid,
+) -> Result<Occurrence> {
+    sqlx::query_as!(
+        Occurrence,
+        r#"
+        SELECT id AS "id!", form_id AS "form_id!", seq AS "seq!", start_time AS "start_time!",
+            end_time AS "end_time!", location AS "location!", user_limit, tier AS "tier!",
+            cancelled AS "cancelled!", created_at AS "created_at!"
+        FROM records.effective_occurrences
+        WHERE form_id = $1 AND id = $2
+        "#,
+        form_id,
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound("Occurrence not found".into()))
+}
+
+/// Expands a session form into the `(start_time, end_time)` of each of its occurrences.
+/// Series longer than `MAX_OCCURRENCES` are refused rather than cut short.
+pub fn expand(form: &SessionForm) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>> {
+    let (Some(_), Some(recurrence_end)) = (&form.recurrence, form.recurrence_end) else {
+        return Ok(vec![(form.start_time, form.end_time)]);
+    };
+
+    let local_start = form.start_time.with_timezone(&SCHEDULE_TZ).naive_local();
+    let local_end = form.end_time.with_timezone(&SCHEDULE_TZ).naive_local();
+
+    let mut occurrences = Vec::new();
+    for n in 0..=MAX_OCCURRENCES {
+        let times = step(local_start, form, n)
+            .and_then(to_utc)
+            .zip(step(local_end, form, n).and_then(to_utc));
+        match times {
+            Some((start, _)) if start <= recurrence_end && n == MAX_OCCURRENCES => {
+                return Err(Error::UnprocessableEntity(format!(
+                    "A series can have at most {} occurrences",
+                    MAX_OCCURRENCES
+                )))
+            }
+            Some((start, end)) if start <= recurrence_end => occurrences.push((start, end)),
+            _ => break,
+        }
+    }
+    Ok(occurrences)
+}
+
+/// Regenerates the stored occurrences of a form. Occurrences are matched by position in the
+/// series, like exceptions are, so moving the series keeps each date's bookings. An edit that
+/// would drop a date someone has booked, queued for or entered the ballot of is refused;
+/// cancel that date instead.
+pub async fn sync_occurrences(conn: &mut sqlx::PgConnection, form: &SessionForm) -> Result<()> {
+    let occurrences = expand(form)?;
+    let seqs: Vec<i32> = (0..occurrences.len() as i32).collect();
+    let start_times: Vec<DateTime<Utc>> = occurrences.iter().map(|o| o.0).collect();
+    let end_times: Vec<DateTime<Utc>> = occurrences.iter().map(|o| o.1).collect();
+    let len = occurrences.len() as i32;
+
+    let booked = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!" FROM records.session_occurrences o
+        WHERE o.form_id = $1 AND o.seq >= $2
+            AND (EXISTS (SELECT 1 FROM records.bookings WHERE occurrence_id = o.id)
+                OR EXISTS (SELECT 1 FROM records.waitlist WHERE occurrence_id = o.id)
+                OR EXISTS (SELECT 1 FROM records.ballot_entries WHERE occurrence_id = o.id))
+        "#,
+        form.id,
+        len
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if booked > 0 {
+        return Err(Error::Conflict(format!(
+            "{} dates that would leave the series have bookings, cancel them instead",
+            booked
+        )));
+    }
+
+    sqlx::query!(
+        "DELETE FROM records.session_occurrences WHERE form_id = $1 AND seq >= $2",
+        form.id,
+        len
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE records.session_occurrences o
+        SET start_time = n.start_time, end_time = n.end_time
+        FROM UNNEST($2::int[], $3::timestamptz[], $4::timestamptz[]) AS n(seq, start_time, end_time)
+        WHERE o.form_id = $1 AND o.seq = n.seq
+        "#,
+        form.id,
+        &seqs[..],
+        &start_times[..],
+        &end_times[..]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO records.session_occurrences(form_id, seq, start_time, end_time)
+        SELECT $1, n.* FROM UNNEST($2::int[], $3::timestamptz[], $4::timestamptz[])
+            AS n(seq, start_time, end_time)
+        ON CONFLICT (form_id, seq) DO NOTHING
+        "#,
+        form.id,
+        &seqs[..],
+        &start_times[..],
+        &end_times[..]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn list_occurrences(
+    State(pool): State<sqlx::PgPool>,
+    _scope: RequireScope<SessionsRead>,
+    Query(filter): Query<OccurrenceFilter>,
+) -> Result<Json<Vec<Occurrence>>> {
+    let occurrences = sqlx::query_as!(
+        Occurrence,
+        r#"
+        SELECT id AS "id!", form_id AS "form_id!", seq AS "seq!", start_time AS "start_time!",
+            end_time AS "end_time!", location AS "location!", user_limit, tier AS "tier!",
+            cancelled AS "cancelled!", created_at AS "created_at!"
+        FROM records.effective_occurrences
+        WHERE ($1::timestamptz IS NULL OR end_time >= $1)
+            AND ($2::timestamptz IS NULL OR start_time <= $2)
+        ORDER BY start_time
+        "#,
+        filter.from,
+        filter.to
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(occurrences))
+}
+
+async fn list_session_occurrences(
+    State(pool): State<sqlx::PgPool>,
+    _scope: RequireScope<SessionsRead>,
+    Path(id): Path<Uuid>,
+    Query(filter): Query<OccurrenceFilter>,
+) -> Result<Json<Vec<Occurrence>>> {
+    let occurrences = sqlx::query_as!(
+        Occurrence,
+        r#"
+        SELECT id AS "id!", form_id AS "form_id!", seq AS "seq!", start_time AS "start_time!",
+            end_time AS "end_time!", location AS "location!", user_limit, tier AS "tier!",
+            cancelled AS "cancelled!", created_at AS "created_at!"
+        FROM records.effective_occurrences
+        WHERE form_id = $1
+            AND ($2::timestamptz IS NULL OR end_time >= $2)
+            AND ($3::timestamptz IS NULL OR start_time <= $3)
+        ORDER BY seq
+        "#,
+        id,
+        filter.from,
+        filter.to
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(occurrences))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::types::PgInterval;
+
+    fn form(start: &str, hours: i64, recurrence: PgInterval, recurrence_end: &str) -> SessionForm {
+        let start_time: DateTime<Utc> = start.parse().unwrap();
+        SessionForm {
+            id: Uuid::new_v4(),
+            author_id: Uuid::new_v4(),
+            title: "Club night".into(),
+            description: String::new(),
+            location: "Hall".into(),
+            tier: 0,
+            start_time,
+            end_time: start_time + chrono::Duration::hours(hours),
+            recurrence: Some(recurrence),
+            recurrence_end: Some(recurrence_end.parse().unwrap()),
+            user_limit: None,
+            created_at: Utc::now(),
+            team_booking_window: None,
+            member_booking_window: None,
+            non_member_booking_window: None,
+            allocation: "first_come".into(),
+            ballot_cutoff: None,
+            ballot_weighting: "none".into(),
+        }
+    }
+
+    fn weeks(n: i32) -> PgInterval {
+        PgInterval {
+            months: 0,
+            days: 7 * n,
+            microseconds: 0,
+        }
+    }
+
+    fn months(n: i32) -> PgInterval {
+        PgInterval {
+            months: n,
+            days: 0,
+            microseconds: 0,
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn weekly_keeps_local_time_across_dst() {
+        // 19:00 GMT before the clocks go forward on 31 March, 19:00 BST after
+        let occurrences = expand(&form(
+            "2030-03-24T19:00:00Z",
+            2,
+            weeks(1),
+            "2030-04-01T00:00:00Z",
+        ))
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                (utc("2030-03-24T19:00:00Z"), utc("2030-03-24T21:00:00Z")),
+                (utc("2030-03-31T18:00:00Z"), utc("2030-03-31T20:00:00Z")),
+            ]
+        );
+
+        // and back again when they go back on 27 October
+        let occurrences = expand(&form(
+            "2030-10-20T18:00:00Z",
+            2,
+            weeks(1),
+            "2030-10-28T00:00:00Z",
+        ))
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                (utc("2030-10-20T18:00:00Z"), utc("2030-10-20T20:00:00Z")),
+                (utc("2030-10-27T19:00:00Z"), utc("2030-10-27T21:00:00Z")),
+            ]
+        );
+    }
+
+    #[test]
+    fn monthly_from_the_31st_clamps_without_drifting() {
+        let starts: Vec<_> = expand(&form(
+            "2030-01-31T19:00:00Z",
+            2,
+            months(1),
+            "2030-05-01T00:00:00Z",
+        ))
+        .unwrap()
+        .into_iter()
+        .map(|(start, _)| start)
+        .collect();
+        assert_eq!(
+            starts,
+            vec![
+                utc("2030-01-31T19:00:00Z"),
+                utc("2030-02-28T19:00:00Z"),
+                utc("2030-03-31T18:00:00Z"),
+                utc("2030-04-30T18:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn occurrence_cap() {
+        // daily from 1 January 2030, so the 520th date is 4 June 2031
+        let day = PgInterval {
+            months: 0,
+            days: 1,
+            microseconds: 0,
+        };
+        let exact = expand(&form(
+            "2030-01-01T12:00:00Z",
+            1,
+            day.clone(),
+            "2031-06-04T12:00:00Z",
+        ));
+        assert_eq!(exact.unwrap().len(), MAX_OCCURRENCES as usize);
+
+        let over = expand(&form(
+            "2030-01-01T12:00:00Z",
+            1,
+            day,
+            "2031-06-05T12:00:00Z",
+        ));
+        assert!(matches!(over, Err(Error::UnprocessableEntity(_))));
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Json, Query, State},
+    http::{
+        header::{LOCATION, USER_AGENT},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, instrument};
+
+use crate::http::cookies::{self, Transport};
+use crate::http::login_events::{self, Attempt};
+use crate::http::token::{self, AuthError};
+use crate::http::{mfa, users, AppState, ClientIp, User};
+use crate::{password, Error, Result};
+
+/// How long a user has to get through the provider's login page
+const STATE_TTL_MINUTES: i64 = 10;
+
+/// Names are stored as `varchar(30)`
+const NAME_MAX_LENGTH: usize = 30;
+
+/// Read from `OIDC_*` variables. Single sign-on is off unless `OIDC_ISSUER` is set, which can
+/// point at a local mock provider for testing.
+struct OidcConfig {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: String,
+    shortcode_claim: String,
+    cid_claim: String,
+    /// Where browsers are sent after logging in with cookies
+    post_login_redirect: Option<String>,
+}
+
+static CONFIG: Lazy<Option<OidcConfig>> = Lazy::new(|| {
+    let issuer = dotenvy::var("OIDC_ISSUER").ok()?;
+    Some(OidcConfig {
+        issuer: issuer.trim_end_matches('/').to_string(),
+        client_id: dotenvy::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID must be set"),
+        client_secret: dotenvy::var("OIDC_CLIENT_SECRET").ok(),
+        redirect_uri: dotenvy::var("OIDC_REDIRECT_URI").expect("OIDC_REDIRECT_URI must be set"),
+        scopes: dotenvy::var("OIDC_SCOPES").unwrap_or_else(|_| "openid profile email".into()),
+        shortcode_claim: dotenvy::var("OIDC_SHORTCODE_CLAIM")
+            .unwrap_or_else(|_| "preferred_username".into()),
+        cid_claim: dotenvy::var("OIDC_CID_CLAIM").unwrap_or_else(|_| "cid".into()),
+        post_login_redirect: dotenvy::var("OIDC_POST_LOGIN_REDIRECT").ok(),
+    })
+});
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeParams {
+    #[serde(default)]
+    pub keep_login: bool,
+    #[serde(default)]
+    pub transport: Transport,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/oidc/authorize", get(authorize))
+        .route("/oidc/callback", get(callback))
+}
+
+fn config() -> Result<&'static OidcConfig> {
+    CONFIG
+        .as_ref()
+        .ok_or_else(|| Error::NotFound("Single sign-on is not configured".into()))
+}
+
+async fn discover(config: &OidcConfig) -> Result<Discovery> {
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    Ok(reqwest::get(url).await?.error_for_status()?.json().await?)
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Sends the browser to the provider, remembering what is needed to finish the login. The
+/// state also goes in a cookie, so that only the browser that started a login can finish it.
+#[instrument(level = "trace", skip(pool, jar))]
+async fn authorize(
+    State(pool): State<sqlx::PgPool>,
+    jar: CookieJar,
+    Query(params): Query<AuthorizeParams>,
+) -> Result<(CookieJar, Redirect)> {
+    let config = config()?;
+    let discovery = discover(config).await?;
+
+    let state = random_token();
+    let nonce = random_token();
+    let code_verifier = random_token();
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    sqlx::query!("DELETE FROM auth.oidc_states WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(&pool)
+        .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.oidc_states(state, code_verifier, nonce, keep_login, use_cookies, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        state,
+        code_verifier,
+        nonce,
+        params.keep_login,
+        params.transport == Transport::Cookie,
+        Utc::now() + Duration::minutes(STATE_TTL_MINUTES)
+    )
+    .execute(&pool)
+    .await?;
+
+    let request = reqwest::Client::new()
+        .get(&discovery.authorization_endpoint)
+        .query(&[
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_uri),
+            ("scope", &config.scopes),
+            ("state", &state),
+            ("nonce", &nonce),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
+        ])
+        .build()?;
+    let jar = jar.add(cookies::oidc_state(state, STATE_TTL_MINUTES));
+    Ok((jar, Redirect::to(request.url().as_str())))
+}
+
+/// Where the provider sends the browser back to. Swaps the code for an ID token and logs the
+/// user in, creating their account on first use.
+#[instrument(level = "trace", skip(pool, headers, params))]
+async fn callback(
+    State(pool): State<sqlx::PgPool>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<(CookieJar, Response)> {
+    let config = config()?;
+    // stops someone else's browser from being logged in to the attacker's account with a
+    // callback link for a login the attacker started
+    let jar = CookieJar::from_headers(&headers);
+    let started_here = jar.get(cookies::OIDC_STATE_COOKIE).is_some_and(|cookie| {
+        cookies::constant_time_eq(cookie.value().as_bytes(), params.state.as_bytes())
+    });
+    if !started_here {
+        error!(name: "exception_error", "Single sign-on callback without a matching state cookie");
+        return Err(Error::from(AuthError::InvalidToken));
+    }
+    let jar = jar.add(cookies::oidc_state_removal());
+
+    let login = sqlx::query!(
+        r#"
+        DELETE FROM auth.oidc_states WHERE state = $1 AND expires_at > CURRENT_TIMESTAMP
+        RETURNING code_verifier, nonce, keep_login, use_cookies
+        "#,
+        params.state
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| {
+        error!(name: "exception_error", "Single sign-on callback with unknown or expired state");
+        AuthError::InvalidToken
+    })?;
+    if let Some(provider_error) = params.error {
+        error!(name: "exception_error", "Identity provider refused the login: {}", provider_error);
+        return Err(Error::from(AuthError::WrongCredentials));
+    }
+    let code = params.code.ok_or(AuthError::MissingCredentials)?;
+
+    let discovery = discover(config).await?;
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", &code),
+        ("redirect_uri", &config.redirect_uri),
+        ("client_id", &config.client_id),
+        ("code_verifier", &login.code_verifier),
+    ];
+    if let Some(client_secret) = &config.client_secret {
+        form.push(("client_secret", client_secret));
+    }
+    let response = reqwest::Client::new()
+        .post(&discovery.token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if response.status().is_client_error() {
+        error!(name: "exception_error", "Identity provider refused the code: {}", response.status());
+        return Err(Error::from(AuthError::InvalidToken));
+    }
+    let tokens: TokenResponse = response.error_for_status()?.json().await?;
+
+    let claims = verify_id_token(config, &discovery, &tokens.id_token).await?;
+    if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+        error!(name: "exception_error", "ID token nonce does not match the login");
+        return Err(Error::from(AuthError::InvalidToken));
+    }
+    let user = find_or_create_user(&pool, config, &discovery.issuer, &claims).await?;
+
+    let transport = if login.use_cookies {
+        Transport::Cookie
+    } else {
+        Transport::Bearer
+    };
+    if mfa::is_enabled(&pool, user.id).await? {
+        let challenge = mfa::create_challenge(&pool, user.id, login.keep_login, None).await?;
+        let response = match (transport, &config.post_login_redirect) {
+            (Transport::Cookie, Some(url)) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                Redirect::to(&format!(
+                    "{url}{separator}mfa_challenge={}",
+                    challenge.challenge_id
+                ))
+                .into_response()
+            }
+            _ => (StatusCode::ACCEPTED, Json(challenge)).into_response(),
+        };
+        return Ok((jar, response));
+    }
+
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    login_events::record(
+        &pool,
+        Attempt {
+            user_id: Some(user.id),
+            shortcode: &user.shortcode,
+            kind: "login",
+            ip,
+            user_agent,
+            failure_reason: None,
+        },
+    )
+    .await?;
+    let mut response = token::issue_tokens(
+        &pool,
+        &user,
+        login.keep_login,
+        None,
+        user_agent,
+        false,
+        transport,
+    )
+    .await?;
+
+    // browsers arrive here from the provider, so send them on to the frontend
+    if let (Transport::Cookie, Some(url)) = (transport, &config.post_login_redirect) {
+        *response.status_mut() = StatusCode::SEE_OTHER;
+        response.headers_mut().insert(
+            LOCATION,
+            HeaderValue::from_str(url).map_err(|_| {
+                Error::UnprocessableEntity("OIDC_POST_LOGIN_REDIRECT is not a valid URL".into())
+            })?,
+        );
+    }
+    Ok((jar, response))
+}
+
+/// Checks the ID token's signature against the provider's published keys, and its issuer and
+/// audience against our configuration
+async fn verify_id_token(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    id_token: &str,
+) -> Result<IdClaims> {
+    let invalid = |e: jsonwebtoken::errors::Error| {
+        error!(name: "token_decoding_error", "Cannot verify ID token: {}", e);
+        AuthError::InvalidToken
+    };
+    let header = jsonwebtoken::decode_header(id_token).map_err(invalid)?;
+    // the provider's keys are public, so a token signed with a shared secret proves nothing
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        error!(name: "token_decoding_error", "ID token signed with {:?}", header.alg);
+        return Err(Error::from(AuthError::InvalidToken));
+    }
+
+    let jwks: JwkSet = reqwest::get(&discovery.jwks_uri)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or(AuthError::InvalidToken)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+    let token_data = jsonwebtoken::decode::<IdClaims>(
+        id_token,
+        &DecodingKey::from_jwk(jwk).map_err(invalid)?,
+        &validation,
+    )
+    .map_err(invalid)?;
+    Ok(token_data.claims)
+}
+
+fn claim(claims: &IdClaims, name: &str) -> Result<String> {
+    match claims.other.get(name) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(Value::Number(value)) => Ok(value.to_string()),
+        _ => Err(Error::UnprocessableEntity(format!(
+            "Identity provider did not return the {name} claim"
+        ))),
+    }
+}
+
+/// Checks a name from the provider against the rules for names given at registration
+fn check_name(name: Option<&String>, claim: &str) -> Result<String> {
+    match name {
+        Some(name)
+            if name.chars().count() <= NAME_MAX_LENGTH && users::NAME_REGEX.is_match(name) =>
+        {
+            Ok(name.clone())
+        }
+        _ => {
+            error!(name: "exception_error", "Single sign-on with an unusable {}", claim);
+            Err(Error::UnprocessableEntity(format!(
+                "Identity provider returned an unusable {claim}"
+            )))
+        }
+    }
+}
+
+/// Finds the account linked to the provider's subject, else links the account with the same
+/// shortcode and CID, else creates one at the tier the membership records give. Two first
+/// logins at once both end up with the account and link that the first one made.
+async fn find_or_create_user(
+    pool: &sqlx::PgPool,
+    config: &OidcConfig,
+    issuer: &str,
+    claims: &IdClaims,
+) -> Result<User> {
+    let mut tx = pool.begin().await?;
+    if let Some(user) = find_linked_user(&mut tx, issuer, &claims.sub).await? {
+        return Ok(user);
+    }
+
+    // usernames may come as an address, e.g. abc123@ic.ac.uk
+    let shortcode = claim(claims, &config.shortcode_claim)?
+        .split('@')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let cid = claim(claims, &config.cid_claim)?;
+
+    let existing = find_user_by_shortcode(&mut tx, &shortcode).await?;
+    let user = match existing {
+        Some(user) => user,
+        None => {
+            let first_name = check_name(claims.given_name.as_ref(), "given_name")?;
+            let surname = check_name(claims.family_name.as_ref(), "family_name")?;
+            let tier = users::check_tier(pool, &cid, &shortcode).await?;
+            // there's no password to log in with until the user sets one through a reset
+            let unusable_password = password::hash(random_token()).await?;
+            sqlx::query!(
+                "DELETE FROM auth.pending_users WHERE shortcode = $1",
+                shortcode
+            )
+            .execute(&mut *tx)
+            .await?;
+            let created = sqlx::query_as!(
+                User,
+                r#"
+                INSERT INTO auth.users(id, first_name, surname, shortcode, cid, password, admin, tier)
+                VALUES ($1, $2, $3, $4, $5, $6, false, $7)
+                ON CONFLICT DO NOTHING
+                RETURNING *
+                "#,
+                crate::http::defaults::default_uuid(),
+                first_name,
+                surname,
+                shortcode,
+                cid,
+                unusable_password,
+                tier
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            match created {
+                Some(user) => {
+                    info!("Created user {} through single sign-on", user.id);
+                    user
+                }
+                // someone registered the shortcode or CID meanwhile
+                None => find_user_by_shortcode(&mut tx, &shortcode)
+                    .await?
+                    .ok_or_else(|| {
+                        Error::Conflict("An account with this CID already exists".into())
+                    })?,
+            }
+        }
+    };
+    if user.cid != cid {
+        error!(name: "exception_error", "Single sign-on for {} with a different CID", shortcode);
+        return Err(Error::Conflict(
+            "An account with this shortcode is registered with another CID".into(),
+        ));
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.user_identities(issuer, subject, user_id) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        issuer,
+        claims.sub,
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    // whichever login linked the subject first decides the account
+    let user = find_linked_user(&mut tx, issuer, &claims.sub)
+        .await?
+        .ok_or_else(|| Error::Conflict("Single sign-on account link was removed".into()))?;
+    tx.commit().await?;
+    Ok(user)
+}
+
+async fn find_linked_user(
+    conn: &mut sqlx::PgConnection,
+    issuer: &str,
+    subject: &str,
+) -> Result<Option<User>> {
+    let user = sqlx::query_as!(
+        User,
+        r#"
+        SELECT users.* FROM auth.user_identities
+        JOIN auth.users ON users.id = user_identities.user_id
+        WHERE issuer = $1 AND subject = $2
+        "#,
+        issuer,
+        subject
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(user)
+}
+
+async fn find_user_by_shortcode(
+    conn: &mut sqlx::PgConnection,
+    shortcode: &str,
+) -> Result<Option<User>> {
+    let user = sqlx::query_as!(
+        User,
+        "SELECT * FROM auth.users WHERE shortcode = $1",
+        shortcode
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(user)
+}
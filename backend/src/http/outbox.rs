@@ -0,0 +1,20 @@
+use crate::http::{AppState, RequireAdmin};
+use crate::mail::outbox::{self, FailedEmail};
+use crate::Result;
+use axum::{
+    extract::{Json, State},
+    routing::get,
+    Router,
+};
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new().route("/mail/failed", get(list_failed))
+}
+
+/// Emails the outbox gave up on, with the error from their last attempt
+async fn list_failed(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+) -> Result<Json<Vec<FailedEmail>>> {
+    Ok(Json(outbox::failed(&pool).await?))
+}
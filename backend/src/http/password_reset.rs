@@ -0,0 +1,163 @@
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    routing::post,
+    Router,
+};
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, instrument};
+use validator::Validate;
+
+use crate::http::token::{self, AuthError};
+use crate::http::users::PASSWORD_REGEX;
+use crate::http::AppState;
+use crate::mail::{outbox, templates};
+use crate::{password, Error, Result};
+
+/// How long a reset token can be used for after it is issued
+const RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+/// Minimum time between two reset emails to the same account
+const RESET_COOLDOWN_SECONDS: i64 = 300;
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPayload {
+    pub shortcode: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ResetPayload {
+    pub token: String,
+    #[validate(length(min=8, max=32), regex(path = *PASSWORD_REGEX))]
+    pub password: String,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/forgot", post(forgot_password))
+        .route("/reset", post(reset_password))
+}
+
+/// Only the hash of a reset token is stored, so a leaked table cannot be used to reset passwords
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Emails a reset token to the user. The work is done in the background and the response is
+/// the same, in content and timing, whether or not the shortcode belongs to an account, so this
+/// cannot be used to find out who is registered.
+#[instrument(level = "trace", skip(pool))]
+async fn forgot_password(
+    State(pool): State<sqlx::PgPool>,
+    Json(payload): Json<ForgotPayload>,
+) -> StatusCode {
+    tokio::spawn(async move {
+        if let Err(e) = issue_reset_token(&pool, &payload.shortcode).await {
+            error!(name: "exception_error", "Cannot issue password reset token: {}", e);
+        }
+    });
+    StatusCode::ACCEPTED
+}
+
+/// Stores a new reset token for the account and queues it to be emailed. Limited to one email
+/// per `RESET_COOLDOWN_SECONDS`, silently, as an error would give away that the account exists.
+async fn issue_reset_token(pool: &sqlx::PgPool, shortcode: &str) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    // locking the user keeps two requests at once from both getting past the cooldown
+    let user = sqlx::query!(
+        r#"
+        SELECT id, shortcode, first_name,
+            (SELECT MAX(created_at) FROM auth.password_resets r WHERE r.user_id = u.id) AS token_sent_at
+        FROM auth.users u WHERE shortcode = $1
+        FOR UPDATE
+        "#,
+        shortcode
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(user) = user else {
+        info!("Password reset requested for unknown shortcode");
+        return Ok(());
+    };
+    if let Some(token_sent_at) = user.token_sent_at {
+        if Utc::now() < token_sent_at + Duration::seconds(RESET_COOLDOWN_SECONDS) {
+            info!(
+                "Password reset for {} requested again within the cooldown",
+                user.id
+            );
+            return Ok(());
+        }
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let reset_token = hex::encode(bytes);
+    let expires_at = Utc::now() + Duration::minutes(RESET_TOKEN_TTL_MINUTES);
+
+    // only the most recently requested token is valid
+    sqlx::query!(
+        "DELETE FROM auth.password_resets WHERE user_id = $1 AND used_at IS NULL",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO auth.password_resets(user_id, token_hash, expires_at) VALUES ($1, $2, $3)",
+        user.id,
+        hash_token(&reset_token),
+        expires_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    let message = templates::password_reset(
+        &user.shortcode,
+        &user.first_name,
+        &reset_token,
+        RESET_TOKEN_TTL_MINUTES,
+    );
+    outbox::enqueue(&mut tx, &message).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Sets a new password using a reset token and logs the user out of every device
+#[instrument(level = "trace", skip_all)]
+async fn reset_password(
+    State(pool): State<sqlx::PgPool>,
+    Json(payload): Json<ResetPayload>,
+) -> Result<StatusCode> {
+    payload.validate()?;
+
+    let mut tx = pool.begin().await?;
+    let user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE auth.password_resets SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id
+        "#,
+        hash_token(&payload.token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| {
+        error!(name: "exception_error", "Invalid or expired password reset token");
+        Error::from(AuthError::InvalidToken)
+    })?;
+
+    let password_hash = password::hash(payload.password).await?;
+    sqlx::query!(
+        "UPDATE auth.users SET password = $1 WHERE id = $2",
+        password_hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    token::revoke_refresh_tokens(&mut tx, user_id).await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
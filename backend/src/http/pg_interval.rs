@@ -1,5 +1,4 @@
 use sqlx::postgres::types::PgInterval;
-use serde::{Deserialize, Serialize};
 use serde::ser::{Serializer, SerializeStruct};
 use serde::de::{Deserializer, Error, Visitor, SeqAccess, MapAccess};
 use std::fmt;
@@ -42,6 +41,27 @@ where
             formatter.write_str("sqlx struct PgInterval")
             }
 
+        fn visit_none<E>(self) -> Result<Option<PgInterval>, E>
+        where
+            E: Error,
+        {
+            Ok(None)
+        }
+
+        fn visit_unit<E>(self) -> Result<Option<PgInterval>, E>
+        where
+            E: Error,
+        {
+            Ok(None)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Option<PgInterval>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_struct("PgInterval", FIELDS, PgIntervalVisitor)
+        }
+
         fn visit_seq<V>(self, mut seq: V) -> Result<Option<PgInterval>, V::Error>
         where
             V: SeqAccess<'de>,
@@ -95,6 +115,6 @@ where
         }
     }
     
-    const FIELDS: &'static[&'static str] = &["months", "days", "microseconds"];
-    deserializer.deserialize_struct("PgInterval", FIELDS, PgIntervalVisitor)
+    const FIELDS: &[&str] = &["months", "days", "microseconds"];
+    deserializer.deserialize_option(PgIntervalVisitor)
 }
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use crate::http::token::{self, AccessClaims};
+use crate::http::ClientIp;
+use crate::{Error, Result};
+
+/// Hard cap on the buckets kept. Past it the least recently used half is dropped, which gives
+/// those keys a fresh burst but keeps memory bounded when addresses are sprayed.
+const MAX_TRACKED: usize = 10_000;
+
+/// Up to `burst` requests at once, refilled evenly over `period_seconds`
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub period_seconds: u32,
+}
+
+impl Limit {
+    /// Reads a limit written as `burst/seconds`, e.g. `10/60`, falling back to `default`
+    pub fn from_env(var: &str, default: Limit) -> Limit {
+        let Ok(value) = dotenvy::var(var) else {
+            return default;
+        };
+        let parsed = value
+            .split_once('/')
+            .and_then(|(burst, period)| {
+                Some((burst.trim().parse().ok()?, period.trim().parse().ok()?))
+            })
+            .filter(|&(burst, period)| burst > 0 && period > 0);
+        match parsed {
+            Some((burst, period_seconds)) => Limit {
+                burst,
+                period_seconds,
+            },
+            None => {
+                warn!(
+                    "Ignoring malformed {}={}, expected burst/seconds",
+                    var, value
+                );
+                default
+            }
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.burst) / f64::from(self.period_seconds)
+    }
+
+    /// A bucket left alone for this long has refilled, so forgetting it changes nothing
+    fn idle_after(&self) -> Duration {
+        Duration::from_secs(u64::from(self.period_seconds))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+impl Buckets {
+    /// Drops the buckets that have refilled. Runs at most once per period, so its cost is
+    /// spread over the requests in between.
+    fn sweep(&mut self, now: Instant, idle_after: Duration) {
+        if now.duration_since(self.swept) < idle_after {
+            return;
+        }
+        self.by_key
+            .retain(|_, bucket| now.duration_since(bucket.updated) < idle_after);
+        self.swept = now;
+    }
+
+    /// Drops the least recently used half of the buckets
+    fn evict(&mut self) {
+        let mut updated: Vec<Instant> = self.by_key.values().map(|bucket| bucket.updated).collect();
+        let middle = updated.len() / 2;
+        let (_, &mut cutoff, _) = updated.select_nth_unstable(middle);
+        self.by_key.retain(|_, bucket| bucket.updated > cutoff);
+        warn!(
+            "Rate limiter reached {} buckets, dropped the least recently used",
+            MAX_TRACKED
+        );
+    }
+}
+
+/// Token buckets for one group of routes, keyed by user or client address. State is per
+/// process; login attempts are also throttled in the database by `login_throttles`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Arc<Self> {
+        Arc::new(RateLimiter {
+            limit,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        })
+    }
+
+    /// Takes a token for `key`, or returns the seconds until one is available
+    fn acquire(&self, key: String) -> std::result::Result<(), u64> {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+        let rate = self.limit.refill_per_second();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+
+        buckets.sweep(now, self.limit.idle_after());
+        if buckets.by_key.len() >= MAX_TRACKED && !buckets.by_key.contains_key(&key) {
+            buckets.evict();
+        }
+
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+}
+
+/// Limits logged-in users by id and everyone else by address. Requests with neither, which
+/// only happens when the server runs without connect info, are let through.
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    let key = match (req.extensions().get::<AccessClaims>(), ip) {
+        (Some(claims), _) => Some(format!("user:{}", claims.user_id)),
+        (None, Some(ip)) => Some(format!("ip:{}", ip)),
+        (None, None) => None,
+    };
+    take(&limiter, key, &req)?;
+    Ok(next.run(req).await)
+}
+
+/// Limits token refreshes by the refresh token family being rotated, so people sharing an
+/// address don't share a budget. Requests without a valid refresh token fall back to the
+/// address.
+pub async fn limit_refresh(
+    State(limiter): State<Arc<RateLimiter>>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    let key = match token::refresh_family(req.headers()) {
+        Some(family) => Some(format!("family:{}", family)),
+        None => ip.map(|ip| format!("ip:{}", ip)),
+    };
+    take(&limiter, key, &req)?;
+    Ok(next.run(req).await)
+}
+
+fn take(limiter: &RateLimiter, key: Option<String>, req: &Request) -> Result<()> {
+    if let Some(key) = key {
+        if let Err(wait) = limiter.acquire(key) {
+            warn!("Rate limited {} {}", req.method(), req.uri().path());
+            return Err(Error::TooManyRequests(wait));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    routing::{get, put},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::http::{AppState, RequireAdmin};
+use crate::{Error, Result};
+
+/// A permission that can be required with `RequirePermission`
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+macro_rules! permission {
+    ($(#[$doc:meta])* $marker:ident, $name:literal) => {
+        $(#[$doc])*
+        pub struct $marker;
+
+        impl Permission for $marker {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+permission!(
+    /// Create sessions and change sessions authored by others
+    ManageSessions,
+    "manage_sessions"
+);
+permission!(
+    /// See the membership list
+    ViewMembers,
+    "view_members"
+);
+permission!(
+    /// See payment and order data
+    ViewPayments,
+    "view_payments"
+);
+
+#[derive(Debug, Serialize)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct UserRole {
+    pub role: String,
+    pub granted_by: Option<Uuid>,
+    pub granted_at: DateTime<Utc>,
+}
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/roles", get(list_roles))
+        .route("/users/:id/roles", get(list_user_roles))
+        .route(
+            "/users/:id/roles/:role",
+            put(grant_role).delete(revoke_role),
+        )
+}
+
+/// The permissions a user has through their roles. They are put in the access token, so a
+/// grant or revoke applies from the user's next login or refresh.
+pub async fn user_permissions(pool: &sqlx::PgPool, user_id: Uuid) -> Result<Vec<String>> {
+    let permissions = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT rp.permission FROM auth.user_roles ur
+        JOIN auth.role_permissions rp ON rp.role = ur.role
+        WHERE ur.user_id = $1
+        ORDER BY rp.permission
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(permissions)
+}
+
+async fn list_roles(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+) -> Result<Json<Vec<Role>>> {
+    let roles = sqlx::query!(
+        r#"
+        SELECT r.name, r.description,
+            ARRAY_REMOVE(ARRAY_AGG(rp.permission ORDER BY rp.permission), NULL) AS "permissions!"
+        FROM auth.roles r
+        LEFT JOIN auth.role_permissions rp ON rp.role = r.name
+        GROUP BY r.name
+        ORDER BY r.name
+        "#
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|row| Role {
+        name: row.name,
+        description: row.description,
+        permissions: row.permissions,
+    })
+    .collect();
+
+    Ok(Json(roles))
+}
+
+async fn list_user_roles(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<UserRole>>> {
+    let roles = sqlx::query_as!(
+        UserRole,
+        "SELECT role, granted_by, granted_at FROM auth.user_roles WHERE user_id = $1 ORDER BY role",
+        id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(roles))
+}
+
+async fn grant_role(
+    State(pool): State<sqlx::PgPool>,
+    RequireAdmin(claims): RequireAdmin,
+    Path((id, role)): Path<(Uuid, String)>,
+) -> Result<StatusCode> {
+    sqlx::query!("SELECT id FROM auth.users WHERE id = $1", id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| Error::NotFound("User not found".into()))?;
+    sqlx::query!("SELECT name FROM auth.roles WHERE name = $1", role)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| Error::NotFound("Role not found".into()))?;
+
+    let granted = sqlx::query!(
+        r#"
+        INSERT INTO auth.user_roles(user_id, role, granted_by)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        id,
+        role,
+        claims.user_id
+    )
+    .execute(&pool)
+    .await?;
+
+    if granted.rows_affected() == 0 {
+        return Ok(StatusCode::OK);
+    }
+    Ok(StatusCode::CREATED)
+}
+
+async fn revoke_role(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Path((id, role)): Path<(Uuid, String)>,
+) -> Result<StatusCode> {
+    let revoked = sqlx::query!(
+        "DELETE FROM auth.user_roles WHERE user_id = $1 AND role = $2",
+        id,
+        role
+    )
+    .execute(&pool)
+    .await?;
+
+    if revoked.rows_affected() == 0 {
+        return Err(Error::NotFound("User does not have this role".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
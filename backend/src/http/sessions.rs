@@ -1,44 +1,400 @@
 use axum::{
-    extract::{State, Form},
+    extract::{Json, Path, Query, State},
     http::StatusCode,
-    routing::{get, post},
+    response::{IntoResponse, Response},
+    routing::get,
     Router,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sqlx::postgres::types::PgInterval;
 use uuid::Uuid;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
-use crate::http::defaults::{default_time, default_uuid};
+use crate::http::defaults::{
+    default_allocation, default_ballot_weighting, default_time, default_uuid,
+};
+use crate::http::token::AccessClaims;
+use crate::http::{
+    ballots, bookings, exceptions, occurrences, AppState, CurrentUser, ManageSessions, Permission,
+    RequirePermission, RequireScope, SessionsRead,
+};
 use crate::{Error, Result};
 
 #[derive(sqlx::FromRow, Debug, Deserialize, Serialize, Validate)]
+#[validate(schema(function = "validate_session_form"))]
 pub struct SessionForm {
     #[serde(default = "default_uuid")]
     pub id: Uuid,
+    #[serde(default)]
     pub author_id: Uuid,
     #[validate(length(min = 1, max = 50))]
     pub title: String,
     pub description: String,
+    #[validate(length(min = 1))]
     pub location: String,
+    #[validate(range(min = 0, max = 2))]
     pub tier: i16,
     pub start_time: chrono::DateTime<chrono::Utc>,
     pub end_time: chrono::DateTime<chrono::Utc>,
     #[serde(default, with = "crate::http::pg_interval")]
     pub recurrence: Option<PgInterval>,
     pub recurrence_end: Option<chrono::DateTime<chrono::Utc>>,
+    #[validate(range(min = 1))]
     pub user_limit: Option<i16>,
     #[serde(default = "default_time")]
     pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, with = "crate::http::pg_interval")]
+    pub team_booking_window: Option<PgInterval>,
+    #[serde(default, with = "crate::http::pg_interval")]
+    pub member_booking_window: Option<PgInterval>,
+    #[serde(default, with = "crate::http::pg_interval")]
+    pub non_member_booking_window: Option<PgInterval>,
+    #[serde(default = "default_allocation")]
+    pub allocation: String, // first_come or ballot
+    #[serde(default, with = "crate::http::pg_interval")]
+    pub ballot_cutoff: Option<PgInterval>,
+    #[serde(default = "default_ballot_weighting")]
+    pub ballot_weighting: String, // none, tier or losses
+}
+
+impl SessionForm {
+    /// When booking an occurrence starting at `start_time` opens for a user of the given tier,
+    /// or `None` if that tier can book as soon as the session is published. A tier without a
+    /// window of its own books from when the next tier up does, so it is never ahead of it.
+    pub fn booking_opens_at(
+        &self,
+        tier: i16,
+        start_time: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let windows = [
+            &self.non_member_booking_window,
+            &self.member_booking_window,
+            &self.team_booking_window,
+        ];
+        let window = windows[tier.clamp(0, 2) as usize..]
+            .iter()
+            .find_map(|window| window.as_ref())?;
+        before(start_time, window)
+    }
+
+    pub fn is_ballot(&self) -> bool {
+        self.allocation == "ballot"
+    }
+
+    /// When entries close for the ballot of an occurrence starting at `start_time`
+    pub fn ballot_closes_at(
+        &self,
+        start_time: chrono::DateTime<chrono::Utc>,
+    ) -> chrono::DateTime<chrono::Utc> {
+        self.ballot_cutoff
+            .as_ref()
+            .and_then(|cutoff| before(start_time, cutoff))
+            .unwrap_or(start_time)
+    }
+}
+
+fn before(
+    time: chrono::DateTime<chrono::Utc>,
+    interval: &PgInterval,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    time.checked_sub_months(chrono::Months::new(interval.months as u32))?
+        .checked_sub_days(chrono::Days::new(interval.days as u64))?
+        .checked_sub_signed(chrono::Duration::microseconds(interval.microseconds))
+}
+
+fn is_negative(interval: &Option<PgInterval>) -> bool {
+    interval
+        .as_ref()
+        .is_some_and(|i| i.months < 0 || i.days < 0 || i.microseconds < 0)
+}
+
+fn validate_session_form(form: &SessionForm) -> std::result::Result<(), ValidationError> {
+    if form.end_time <= form.start_time {
+        return Err(ValidationError::new("end_time_before_start_time"));
+    }
+    if is_negative(&form.team_booking_window)
+        || is_negative(&form.member_booking_window)
+        || is_negative(&form.non_member_booking_window)
+    {
+        return Err(ValidationError::new("booking_window_negative"));
+    }
+    // the team books no later than members, and members no later than everyone else
+    let opens_at = |tier| form.booking_opens_at(tier, form.start_time);
+    if opens_at(2) > opens_at(1) || opens_at(1) > opens_at(0) {
+        return Err(ValidationError::new("booking_window_not_monotonic"));
+    }
+    if !matches!(form.allocation.as_str(), "first_come" | "ballot") {
+        return Err(ValidationError::new("allocation_unknown"));
+    }
+    if !matches!(form.ballot_weighting.as_str(), "none" | "tier" | "losses") {
+        return Err(ValidationError::new("ballot_weighting_unknown"));
+    }
+    if is_negative(&form.ballot_cutoff) {
+        return Err(ValidationError::new("ballot_cutoff_negative"));
+    }
+    match (&form.recurrence, form.recurrence_end) {
+        (Some(interval), Some(recurrence_end)) => {
+            if interval.months < 0
+                || interval.days < 0
+                || interval.microseconds < 0
+                || (interval.months == 0 && interval.days == 0 && interval.microseconds == 0)
+            {
+                return Err(ValidationError::new("recurrence_not_positive"));
+            }
+            if recurrence_end <= form.start_time {
+                return Err(ValidationError::new("recurrence_end_before_start_time"));
+            }
+            Ok(())
+        }
+        (None, None) => Ok(()),
+        _ => Err(ValidationError::new("recurrence_incomplete")),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionFilter {
+    pub tier: Option<i16>,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    pub location: Option<String>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_sessions).post(create_session))
+        .route(
+            "/:id",
+            get(get_session)
+                .patch(update_session)
+                .delete(delete_session),
+        )
+        .merge(occurrences::router())
+        .merge(exceptions::router())
+        .merge(bookings::router())
+        .merge(ballots::router())
+}
+
+/// Only the author of a session or someone who manages sessions may change it
+pub fn check_author(claims: &AccessClaims, author_id: Uuid) -> Result<()> {
+    if claims.user_id == author_id || claims.has_permission(ManageSessions::NAME) {
+        return Ok(());
+    }
+    Err(Error::Forbidden(
+        "Only the author of a session or a session coordinator can change it".into(),
+    ))
 }
 
-pub fn router() -> Router<sqlx::PgPool> {
-    Router::new().route("/create", post(create_session))
+/// Checks `check_author` against a stored session
+pub async fn authorize_edit(
+    conn: &mut sqlx::PgConnection,
+    claims: &AccessClaims,
+    id: Uuid,
+) -> Result<()> {
+    let author_id = sqlx::query_scalar!(
+        "SELECT author_id FROM records.session_forms WHERE id = $1",
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound("Session not found".into()))?;
+    check_author(claims, author_id)
 }
 
 async fn create_session(
     State(pool): State<sqlx::PgPool>,
-    Form(payload): Form<SessionForm>,
-) -> Result<StatusCode> {
-    Ok(StatusCode::OK)
+    RequirePermission(claims, _): RequirePermission<ManageSessions>,
+    Json(mut payload): Json<SessionForm>,
+) -> Result<Response> {
+    payload.author_id = claims.user_id;
+    payload.validate()?;
+
+    let mut tx = pool.begin().await?;
+    let session = sqlx::query_as!(
+        SessionForm,
+        r#"
+        INSERT INTO records.session_forms(id, author_id, title, description, location, tier, start_time, end_time, recurrence, recurrence_end, user_limit, created_at, team_booking_window, member_booking_window, non_member_booking_window, allocation, ballot_cutoff, ballot_weighting)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+        RETURNING *
+        "#,
+        payload.id,
+        payload.author_id,
+        payload.title,
+        payload.description,
+        payload.location,
+        payload.tier,
+        payload.start_time,
+        payload.end_time,
+        payload.recurrence,
+        payload.recurrence_end,
+        payload.user_limit,
+        payload.created_at,
+        payload.team_booking_window,
+        payload.member_booking_window,
+        payload.non_member_booking_window,
+        payload.allocation,
+        payload.ballot_cutoff,
+        payload.ballot_weighting
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    occurrences::sync_occurrences(&mut tx, &session).await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(session)).into_response())
+}
+
+async fn get_session(
+    State(pool): State<sqlx::PgPool>,
+    _scope: RequireScope<SessionsRead>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SessionForm>> {
+    let session = sqlx::query_as!(
+        SessionForm,
+        "SELECT * FROM records.session_forms WHERE id = $1",
+        id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("Session not found".into()))?;
+
+    Ok(Json(session))
+}
+
+async fn list_sessions(
+    State(pool): State<sqlx::PgPool>,
+    _scope: RequireScope<SessionsRead>,
+    Query(filter): Query<SessionFilter>,
+) -> Result<Json<Vec<SessionForm>>> {
+    // a recurring series overlaps the range if any part of it, up to recurrence_end, does
+    let sessions = sqlx::query_as!(
+        SessionForm,
+        r#"
+        SELECT * FROM records.session_forms
+        WHERE ($1::smallint IS NULL OR tier = $1)
+            AND ($2::timestamptz IS NULL OR COALESCE(recurrence_end, end_time) >= $2)
+            AND ($3::timestamptz IS NULL OR start_time <= $3)
+            AND ($4::text IS NULL OR location ILIKE '%' || $4 || '%')
+        ORDER BY start_time
+        "#,
+        filter.tier,
+        filter.from,
+        filter.to,
+        filter.location
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(sessions))
+}
+
+/// Applies a JSON merge patch to the stored session, so `null` clears an optional field
+async fn update_session(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path(id): Path<Uuid>,
+    Json(patch): Json<Value>,
+) -> Result<Json<SessionForm>> {
+    let Value::Object(patch) = patch else {
+        return Err(Error::UnprocessableEntity("Expected a JSON object".into()));
+    };
+
+    // locked so that two edits at once cannot each merge into the same stale values
+    let mut tx = pool.begin().await?;
+    let existing = sqlx::query_as!(
+        SessionForm,
+        "SELECT * FROM records.session_forms WHERE id = $1 FOR UPDATE",
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::NotFound("Session not found".into()))?;
+    check_author(&claims, existing.author_id)?;
+
+    let mut merged =
+        serde_json::to_value(&existing).map_err(|e| Error::UnprocessableEntity(e.to_string()))?;
+    if let Value::Object(fields) = &mut merged {
+        for (key, value) in patch {
+            if matches!(key.as_str(), "id" | "author_id" | "created_at") {
+                continue;
+            }
+            fields.insert(key, value);
+        }
+    }
+    let updated: SessionForm =
+        serde_json::from_value(merged).map_err(|e| Error::UnprocessableEntity(e.to_string()))?;
+    updated.validate()?;
+
+    let session = sqlx::query_as!(
+        SessionForm,
+        r#"
+        UPDATE records.session_forms
+        SET title = $2, description = $3, location = $4, tier = $5, start_time = $6,
+            end_time = $7, recurrence = $8, recurrence_end = $9, user_limit = $10,
+            team_booking_window = $11, member_booking_window = $12, non_member_booking_window = $13,
+            allocation = $14, ballot_cutoff = $15, ballot_weighting = $16
+        WHERE id = $1
+        RETURNING *
+        "#,
+        id,
+        updated.title,
+        updated.description,
+        updated.location,
+        updated.tier,
+        updated.start_time,
+        updated.end_time,
+        updated.recurrence,
+        updated.recurrence_end,
+        updated.user_limit,
+        updated.team_booking_window,
+        updated.member_booking_window,
+        updated.non_member_booking_window,
+        updated.allocation,
+        updated.ballot_cutoff,
+        updated.ballot_weighting
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    occurrences::sync_occurrences(&mut tx, &session).await?;
+
+    // a lowered limit releases the latest bookings and a raised one frees up places, on
+    // every upcoming date
+    let upcoming = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM records.session_occurrences
+        WHERE form_id = $1 AND start_time > now()
+        ORDER BY seq
+        FOR UPDATE
+        "#,
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for occurrence_id in upcoming {
+        let occurrence = occurrences::fetch_occurrence(&mut tx, id, occurrence_id).await?;
+        exceptions::release_bookings(&mut tx, &occurrence).await?;
+        bookings::promote_waitlist(&mut tx, &occurrence).await?;
+    }
+    tx.commit().await?;
+
+    Ok(Json(session))
+}
+
+async fn delete_session(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SessionForm>> {
+    let mut tx = pool.begin().await?;
+    authorize_edit(&mut tx, &claims, id).await?;
+    let session = sqlx::query_as!(
+        SessionForm,
+        "DELETE FROM records.session_forms WHERE id = $1 RETURNING *",
+        id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(session))
 }
@@ -1,29 +1,60 @@
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
-    extract::{Request, State},
-    http::{HeaderMap, StatusCode},
+    extract::{Path, Request, State},
+    http::{
+        header::{AUTHORIZATION, USER_AGENT},
+        HeaderMap, StatusCode,
+    },
     middleware::Next,
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
 use axum_extra::{
+    extract::cookie::CookieJar,
     headers::{authorization::Bearer, Authorization},
     typed_header::{TypedHeader, TypedHeaderRejection},
 };
-use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::jwk::JwkSet;
 use once_cell::sync::Lazy;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tracing::{error, instrument};
+use tracing::{error, instrument, warn};
+
+use crate::http::api_keys;
+use crate::http::cookies::{self, Transport};
+use crate::http::keyring::Keyring;
+use crate::http::login_events::{self, Attempt};
+use crate::http::login_throttles;
+use crate::http::mfa;
+use crate::http::{roles, AppState, ClientIp, CurrentUser, RequireAdmin};
+use crate::{password, Error, Result};
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/login", post(authenticate))
+}
+
+pub fn refresh_router() -> Router<AppState> {
+    Router::new().route("/refresh", get(refresh_token))
+}
 
-use crate::{Error, Result};
+pub fn protected_router() -> Router<AppState> {
+    Router::new().route("/logout", post(logout))
+}
 
-pub fn router() -> Router<sqlx::PgPool> {
+pub fn me_router() -> Router<AppState> {
     Router::new()
-        .route("/login", post(authenticate))
-        .route("/refresh", get(refresh_token))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/:id", delete(delete_session))
+}
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new().route("/users/:id/revoke-tokens", post(revoke_user_tokens))
+}
+
+/// Served at the root as `/.well-known/jwks.json`
+pub fn jwks_router() -> Router<AppState> {
+    Router::new().route("/.well-known/jwks.json", get(jwks))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +65,24 @@ pub struct AccessClaims {
     pub name: String, // full name
     pub tier: i16,    // 0 = user, 1 = member, 2 = team
     pub admin: bool,
+    pub sid: Option<uuid::Uuid>, // refresh token the access token was issued with, if any
+    #[serde(default)]
+    pub permissions: Vec<String>, // granted through committee roles
+    #[serde(default)]
+    pub mfa: bool, // logged in with a second factor
+}
+
+impl AccessClaims {
+    /// Whether admin rights can be used with this token, which with `REQUIRE_ADMIN_2FA` set
+    /// needs a login that passed two-factor authentication
+    pub fn is_admin(&self) -> bool {
+        self.admin && (self.mfa || !*mfa::REQUIRE_ADMIN_2FA)
+    }
+
+    /// Admins hold every permission
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.is_admin() || self.permissions.iter().any(|p| p == permission)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,6 +91,7 @@ pub struct RefreshClaims {
     pub exp: usize,
     pub jti: uuid::Uuid,
     pub user_id: uuid::Uuid,
+    pub sid: uuid::Uuid, // refresh token row, stable across rotations
 }
 
 #[derive(Debug, Serialize)]
@@ -66,6 +116,9 @@ struct AuthPayload {
     shortcode: String,
     password: String,
     keep_login: bool,
+    device_label: Option<String>,
+    #[serde(default)]
+    transport: Transport,
 }
 
 #[derive(Debug)]
@@ -74,42 +127,55 @@ pub enum AuthError {
     MissingCredentials,
     TokenCreation,
     InvalidToken,
+    TokenRevoked,
+    TokenReused,
+    NotAdmin,
+    MfaRequired,
+    InsufficientTier { required: i16 },
+    MissingPermission { permission: &'static str },
+    MissingScope { scope: &'static str },
+    ApiKeyNotAllowed,
 }
 
-struct Keys {
-    encoding: EncodingKey,
-    decoding: DecodingKey,
-}
-
-impl Keys {
-    fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
-        }
-    }
-}
+static ACCESS_KEYS: Lazy<Keyring> = Lazy::new(|| Keyring::from_env("ACCESS_JWT"));
 
-const ACCESS_KEYS: Lazy<Keys> = Lazy::new(|| {
-    let secret = dotenvy::var("ACCESS_JWT_SECRET").expect("JWT_SECRET must be set");
-    Keys::new(secret.as_bytes())
-});
+static REFRESH_KEYS: Lazy<Keyring> = Lazy::new(|| Keyring::from_env("REFRESH_JWT"));
 
-const REFRESH_KEYS: Lazy<Keys> = Lazy::new(|| {
-    let secret = dotenvy::var("REFRESH_JWT_SECRET").expect("JWT_SECRET must be set");
-    Keys::new(secret.as_bytes())
-});
+/// Public keys that access tokens can be verified with
+async fn jwks() -> Json<JwkSet> {
+    Json(ACCESS_KEYS.jwks())
+}
 
-#[instrument(name = "auth_via_login", level = "TRACE")]
+#[instrument(name = "auth_via_login", level = "TRACE", skip(pool, headers))]
 async fn authenticate(
     State(pool): State<sqlx::PgPool>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     Json(payload): Json<AuthPayload>,
 ) -> Result<impl IntoResponse> {
     if payload.shortcode.is_empty() || payload.password.is_empty() {
-        error!(
+        error!(name: "exception_error", "Shortcode or password is empty");
         return Err(Error::from(AuthError::MissingCredentials));
     }
 
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    let mut attempt = Attempt {
+        user_id: None,
+        shortcode: &payload.shortcode,
+        kind: "login",
+        ip,
+        user_agent,
+        failure_reason: None,
+    };
+
+    if let Err(e) = login_throttles::check(&pool, &payload.shortcode, ip).await {
+        attempt.failure_reason = Some("throttled");
+        login_events::record(&pool, attempt).await?;
+        return Err(e);
+    }
+
     let selected_user = sqlx::query_as!(
         crate::http::User,
         r#"
@@ -117,204 +183,549 @@ async fn authenticate(
 		"#,
         &payload.shortcode,
     )
-    .fetch_one(&pool)
+    .fetch_optional(&pool)
+    .await?;
+    let Some(selected_user) = selected_user else {
+        password::verify_dummy(payload.password.clone()).await?;
+        attempt.failure_reason = Some("unknown_user");
+        login_events::record(&pool, attempt).await?;
+        login_throttles::record_failure(&pool, &payload.shortcode, ip).await?;
+        return Err(Error::from(AuthError::WrongCredentials));
+    };
+    attempt.user_id = Some(selected_user.id);
+
+    let password_matches = match password::verify(
+        payload.password.clone(),
+        selected_user.password.clone(),
+    )
     .await
-    .map_err(|_| AuthError::WrongCredentials)?;
-
-    let parsed_hash =
-        PasswordHash::new(&selected_user.password).map_err(|_| AuthError::WrongCredentials)?;
-
-    if Argon2::default()
-        .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .is_ok()
     {
-        let expiration = Utc::now()
-            .checked_add_signed(Duration::hours(1))
-            .expect("valid timestamp")
-            .timestamp();
-
-        let claims = AccessClaims {
-            sub: selected_user.shortcode.clone(),
-            exp: expiration as usize,
-            user_id: selected_user.id,
-            name: selected_user.first_name + &selected_user.surname,
-            tier: selected_user.tier,
-            admin: selected_user.admin,
-        };
-
-        let access_token = encode(&Header::default(), &claims, &ACCESS_KEYS.encoding)
-            .map_err(|_| AuthError::TokenCreation)?;
-
-        if payload.keep_login {
-            let expiration = Utc::now()
-                .checked_add_signed(Duration::weeks(12))
-                .expect("valid timestamp")
-                .timestamp();
-
-            let jti = crate::http::defaults::default_uuid();
-            let _ = sqlx::query!(
-                "UPDATE auth.users SET jti = $1 WHERE id = $2",
-                jti,
-                selected_user.id
-            )
-            .execute(&pool)
-            .await
-            .map_err(|_| AuthError::TokenCreation)?;
-
-            let refresh_claims = RefreshClaims {
-                sub: selected_user.shortcode,
-                exp: expiration as usize,
-                user_id: selected_user.id,
-                jti,
-            };
-
-            let refresh_token = encode(&Header::default(), &refresh_claims, &REFRESH_KEYS.encoding)
-                .map_err(|_| AuthError::TokenCreation)?;
+        Err(Error::PasswordHash(e)) => {
+            error!(name: "exception_error", "Stored hash of user {} is invalid: {}", selected_user.id, e);
+            false
+        }
+        result => result?,
+    };
 
-            return Ok((
-                StatusCode::OK,
-                Json(AuthBody::new(access_token, Some(refresh_token))),
+    if password_matches {
+        if password::needs_rehash(&selected_user.password) {
+            rehash_password(&pool, &selected_user, payload.password.clone()).await;
+        }
+        if mfa::is_enabled(&pool, selected_user.id).await? {
+            // the login is recorded once the second factor has been checked
+            let challenge = mfa::create_challenge(
+                &pool,
+                selected_user.id,
+                payload.keep_login,
+                payload.device_label,
             )
-                .into_response());
+            .await?;
+            return Ok((StatusCode::ACCEPTED, Json(challenge)).into_response());
         }
-        return Ok((StatusCode::OK, Json(AuthBody::new(access_token, None))).into_response());
+        login_events::record(&pool, attempt).await?;
+        login_throttles::clear(&pool, &payload.shortcode).await?;
+        return issue_tokens(
+            &pool,
+            &selected_user,
+            payload.keep_login,
+            payload.device_label,
+            user_agent,
+            false,
+            payload.transport,
+        )
+        .await;
     }
+    attempt.failure_reason = Some("wrong_password");
+    login_events::record(&pool, attempt).await?;
+    login_throttles::record_failure(&pool, &payload.shortcode, ip).await?;
     let rand_sleep = rand::thread_rng()
         .gen_range(std::time::Duration::from_millis(100)..=std::time::Duration::from_millis(500));
     tokio::time::sleep(rand_sleep).await;
     Err(Error::from(AuthError::WrongCredentials))
 }
 
+/// Replaces a hash made with outdated Argon2 parameters. Failing to do so is only logged, as
+/// the login itself is fine and it will be tried again next time.
+async fn rehash_password(pool: &sqlx::PgPool, user: &crate::http::User, plain: String) {
+    let result = async {
+        let new_hash = password::hash(plain).await?;
+        // a password changed in the meantime is left alone
+        sqlx::query!(
+            "UPDATE auth.users SET password = $1 WHERE id = $2 AND password = $3",
+            new_hash,
+            user.id,
+            user.password
+        )
+        .execute(pool)
+        .await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Could not rehash password of user {}: {}", user.id, e);
+    }
+}
+
+/// Responds with a new access token, plus a refresh token on a new device session when the
+/// user asked to stay logged in
+pub async fn issue_tokens(
+    pool: &sqlx::PgPool,
+    user: &crate::http::User,
+    keep_login: bool,
+    device_label: Option<String>,
+    user_agent: Option<&str>,
+    mfa: bool,
+    transport: Transport,
+) -> Result<Response> {
+    let permissions = roles::user_permissions(pool, user.id).await?;
+    if !keep_login {
+        let access_token = encode_access_token(user, None, permissions, mfa)?;
+        return Ok(token_response(transport, access_token, None));
+    }
+
+    let sid = crate::http::defaults::default_uuid();
+    let jti = crate::http::defaults::default_uuid();
+    let expires_at = refresh_expiry();
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.refresh_tokens(id, user_id, jti, device_label, user_agent, expires_at, mfa)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        sid,
+        user.id,
+        jti,
+        device_label,
+        user_agent,
+        expires_at,
+        mfa
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        error!(name: "db_error", "Error when storing refresh token in db: {}", e);
+        AuthError::TokenCreation
+    })?;
+
+    let access_token = encode_access_token(user, Some(sid), permissions, mfa)?;
+    let refresh_token = encode_refresh_token(user, sid, jti, expires_at)?;
+    Ok(token_response(
+        transport,
+        access_token,
+        Some((refresh_token, expires_at)),
+    ))
+}
+
+/// Tokens go in the body for API clients, or in HttpOnly cookies for browsers
+fn token_response(
+    transport: Transport,
+    access_token: String,
+    refresh: Option<(String, DateTime<Utc>)>,
+) -> Response {
+    match transport {
+        Transport::Bearer => (
+            StatusCode::OK,
+            Json(AuthBody::new(access_token, refresh.map(|(token, _)| token))),
+        )
+            .into_response(),
+        Transport::Cookie => {
+            let jar =
+                cookies::with_tokens(CookieJar::new(), access_token, access_expiry(), refresh);
+            (StatusCode::NO_CONTENT, jar).into_response()
+        }
+    }
+}
+
+fn access_expiry() -> DateTime<Utc> {
+    Utc::now()
+        .checked_add_signed(Duration::hours(1))
+        .expect("valid timestamp")
+}
+
+fn refresh_expiry() -> DateTime<Utc> {
+    Utc::now()
+        .checked_add_signed(Duration::weeks(12))
+        .expect("valid timestamp")
+}
+
+fn encode_access_token(
+    user: &crate::http::User,
+    sid: Option<uuid::Uuid>,
+    permissions: Vec<String>,
+    mfa: bool,
+) -> Result<String> {
+    let expiration = access_expiry().timestamp();
+
+    let claims = AccessClaims {
+        sub: user.shortcode.clone(),
+        exp: expiration as usize,
+        user_id: user.id,
+        name: user.first_name.clone() + &user.surname,
+        tier: user.tier,
+        admin: user.admin,
+        sid,
+        permissions,
+        mfa,
+    };
+
+    let token = ACCESS_KEYS.encode(&claims).map_err(|e| {
+        error!(name: "token_encoding_error", "Problem creating new access token: {}", e);
+        AuthError::TokenCreation
+    })?;
+    Ok(token)
+}
+
+fn encode_refresh_token(
+    user: &crate::http::User,
+    sid: uuid::Uuid,
+    jti: uuid::Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<String> {
+    let refresh_claims = RefreshClaims {
+        sub: user.shortcode.clone(),
+        exp: expires_at.timestamp() as usize,
+        jti,
+        user_id: user.id,
+        sid,
+    };
+
+    let token = REFRESH_KEYS.encode(&refresh_claims).map_err(|e| {
+        error!(name: "token_encoding_error", "Problem when encoding new refresh token: {}", e);
+        AuthError::TokenCreation
+    })?;
+    Ok(token)
+}
+
 #[instrument(level = "trace", skip_all, fields(token))]
+/// The family of the refresh token sent with a request, if it carries a valid one
+pub fn refresh_family(headers: &HeaderMap) -> Option<uuid::Uuid> {
+    let token = match headers.get(AUTHORIZATION) {
+        Some(value) => value.to_str().ok()?.strip_prefix("Bearer ")?.to_string(),
+        None => CookieJar::from_headers(headers)
+            .get(cookies::REFRESH_COOKIE)?
+            .value()
+            .to_string(),
+    };
+    let token_data = REFRESH_KEYS.decode::<RefreshClaims>(&token).ok()?;
+    Some(token_data.claims.sid)
+}
+
 async fn refresh_token(
     headers: HeaderMap,
+    ClientIp(ip): ClientIp,
     State(pool): State<sqlx::PgPool>,
 ) -> Result<impl IntoResponse> {
-    if let Some(auth_header) = headers.get("Authorization") {
-        let auth_val = auth_header.to_str().map_err(|e| {
-            error!(name: "invalid_header", "Cannot convert auth header to string: {}", e);
-            AuthError::MissingCredentials
-        })?;
-        if let Some(auth_tuple) = auth_val.split_once(' ') {
-            if auth_tuple.0 == "Bearer" {
-                let token = auth_tuple.1;
-                let token_data = decode::<RefreshClaims>(
-                    &token,
-                    &REFRESH_KEYS.decoding,
-                    &Validation::default(),
-                )
-                .map_err(|e| {
-                    error!(name: "token_decoding_error", "Cannot decode token into claims: {}", e);
-                    AuthError::InvalidToken
-                })?;
-
-                let selected_user = sqlx::query_as!(
-                    crate::http::User,
-                    "SELECT * FROM auth.users WHERE id = $1",
-                    &token_data.claims.user_id
-                )
-                .fetch_one(&pool)
-                .await
-                .map_err(|e| {
-                    error!(name: "db_error", "Cannot fetch corresponding jti from db: {}", e);
-                    AuthError::TokenCreation
-                })?;
-                if let Some(jwt_id) = selected_user.jti {
-                    if jwt_id == token_data.claims.jti {
-                        let expiration = Utc::now()
-                            .checked_add_signed(Duration::hours(1))
-                            .expect("valid timestamp")
-                            .timestamp();
-
-                        let claims = AccessClaims {
-                            sub: selected_user.shortcode.clone(),
-                            exp: expiration as usize,
-                            user_id: selected_user.id,
-                            name: selected_user.first_name + &selected_user.surname,
-                            tier: selected_user.tier,
-                            admin: selected_user.admin,
-                        };
-
-                        let access_token =
-                            encode(&Header::default(), &claims, &ACCESS_KEYS.encoding)
-                                .map_err(|e| {
-                                    error!(name: "token_encoding_error", "Problem creating new access token: {}", e);
-                                    AuthError::TokenCreation
-                                })?;
-
-                        let expiration = Utc::now()
-                            .checked_add_signed(Duration::weeks(12))
-                            .expect("valid timestamp")
-                            .timestamp();
-
-                        let jti = crate::http::defaults::default_uuid();
-                        let _ = sqlx::query!(
-                            "UPDATE auth.users SET jti = $1 WHERE id = $2",
-                            jti,
-                            selected_user.id
-                        )
-                        .execute(&pool)
-                        .await
-                        .map_err(|e| {
-                            error!(name: "db_error", "Error when updating jti in db: {}", e);
-                            AuthError::TokenCreation
-                        })?;
-
-                        let refresh_claims = RefreshClaims {
-                            sub: selected_user.shortcode,
-                            exp: expiration as usize,
-                            user_id: selected_user.id,
-                            jti,
-                        };
-
-                        let refresh_token =
-                            encode(&Header::default(), &refresh_claims, &REFRESH_KEYS.encoding)
-                                .map_err(|e| {
-                                    error!(name: "token_encoding_error", "Problem when encoding new refresh token: {}",e);
-                                    AuthError::TokenCreation
-                                })?;
-
-                        return Ok((
-                            StatusCode::OK,
-                            Json(AuthBody::new(access_token, Some(refresh_token))),
-                        )
-                            .into_response());
-                    }
-                }
-                error!(name: "exception_error", "User does not have jti stored in db");
-                return Err(Error::from(AuthError::TokenCreation));
-            }
+    let jar = CookieJar::from_headers(&headers);
+    let (token, transport) = match headers.get(AUTHORIZATION) {
+        Some(auth_header) => {
+            let auth_val = auth_header.to_str().map_err(|e| {
+                error!(name: "invalid_header", "Cannot convert auth header to string: {}", e);
+                AuthError::MissingCredentials
+            })?;
+            let Some(("Bearer", token)) = auth_val.split_once(' ') else {
+                error!(name: "exception_error", "No bearer found in header");
+                return Err(Error::from(AuthError::MissingCredentials));
+            };
+            (token.to_string(), Transport::Bearer)
         }
-        error!(name: "exception_error", "No bearer found in header");
-        return Err(Error::from(AuthError::MissingCredentials));
+        None => {
+            let Some(cookie) = jar.get(cookies::REFRESH_COOKIE) else {
+                error!(name: "exception_error", "No authentication header or cookie found");
+                return Err(Error::from(AuthError::MissingCredentials));
+            };
+            cookies::check_csrf(&jar, &headers)?;
+            (cookie.value().to_string(), Transport::Cookie)
+        }
+    };
+    let token_data = REFRESH_KEYS.decode::<RefreshClaims>(&token).map_err(|e| {
+        error!(name: "token_decoding_error", "Cannot decode token into claims: {}", e);
+        AuthError::InvalidToken
+    })?;
+    let claims = token_data.claims;
+
+    let selected_user = sqlx::query_as!(
+        crate::http::User,
+        "SELECT * FROM auth.users WHERE id = $1",
+        &claims.user_id
+    )
+    .fetch_optional(&pool)
+    .await?;
+    let Some(selected_user) = selected_user else {
+        // the family is normally deleted along with its user, this makes sure of it
+        error!(name: "exception_error", "User {} of refresh token no longer exists", claims.user_id);
+        sqlx::query!(
+            "UPDATE auth.refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1",
+            claims.sid
+        )
+        .execute(&pool)
+        .await?;
+        return Err(Error::from(AuthError::InvalidToken));
+    };
+
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    let mut attempt = Attempt {
+        user_id: Some(selected_user.id),
+        shortcode: &selected_user.shortcode,
+        kind: "refresh",
+        ip,
+        user_agent,
+        failure_reason: None,
+    };
+
+    let mut tx = pool.begin().await?;
+    let family = sqlx::query!(
+        r#"
+        SELECT jti, revoked_at, mfa FROM auth.refresh_tokens
+        WHERE id = $1 AND user_id = $2
+        FOR UPDATE
+        "#,
+        claims.sid,
+        selected_user.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(family) = family else {
+        error!(name: "exception_error", "Refresh token family {} no longer exists", claims.sid);
+        attempt.failure_reason = Some("token_revoked");
+        login_events::record(&pool, attempt).await?;
+        return Err(Error::from(AuthError::TokenRevoked));
+    };
+    if family.revoked_at.is_some() {
+        error!(name: "exception_error", "Refresh token family {} has been revoked", claims.sid);
+        attempt.failure_reason = Some("token_revoked");
+        login_events::record(&pool, attempt).await?;
+        return Err(Error::from(AuthError::TokenRevoked));
+    }
+    if family.jti != claims.jti {
+        // only we can sign refresh tokens, so a valid one with an old jti has already
+        // been rotated away and is being replayed by someone holding a copy
+        warn!(name: "token_reuse", "Rotated refresh token reused for family {}", claims.sid);
+        sqlx::query!(
+            "UPDATE auth.refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1",
+            claims.sid
+        )
+        .execute(&mut *tx)
+        .await?;
+        record_security_event(
+            &mut tx,
+            selected_user.id,
+            "refresh_token_reuse",
+            Some(claims.sid),
+            user_agent,
+        )
+        .await?;
+        tx.commit().await?;
+        attempt.failure_reason = Some("token_reused");
+        login_events::record(&pool, attempt).await?;
+        return Err(Error::from(AuthError::TokenReused));
+    }
+
+    // rotate the jti of this device only, leaving the user's other devices logged in
+    let jti = crate::http::defaults::default_uuid();
+    let expires_at = refresh_expiry();
+    sqlx::query!(
+        r#"
+        UPDATE auth.refresh_tokens
+        SET jti = $1, last_used_at = CURRENT_TIMESTAMP, expires_at = $2
+        WHERE id = $3
+        "#,
+        jti,
+        expires_at,
+        claims.sid
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!(name: "db_error", "Error when rotating refresh token in db: {}", e);
+        AuthError::TokenCreation
+    })?;
+    tx.commit().await?;
+    login_events::record(&pool, attempt).await?;
+
+    let access_token = encode_access_token(
+        &selected_user,
+        Some(claims.sid),
+        roles::user_permissions(&pool, selected_user.id).await?,
+        family.mfa,
+    )?;
+    let refresh_token = encode_refresh_token(&selected_user, claims.sid, jti, expires_at)?;
+    Ok(token_response(
+        transport,
+        access_token,
+        Some((refresh_token, expires_at)),
+    ))
+}
+
+/// Writes an entry to the security audit log
+async fn record_security_event(
+    conn: &mut sqlx::PgConnection,
+    user_id: uuid::Uuid,
+    kind: &str,
+    refresh_token_id: Option<uuid::Uuid>,
+    user_agent: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.security_events(user_id, kind, refresh_token_id, user_agent)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        user_id,
+        kind,
+        refresh_token_id,
+        user_agent
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// A device the user is logged in on with a refresh token
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct RefreshSession {
+    pub id: uuid::Uuid,
+    pub device_label: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub current: bool,
+}
+
+#[instrument(level = "trace", skip_all, fields(user_id = %claims.user_id))]
+async fn list_sessions(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+) -> Result<Json<Vec<RefreshSession>>> {
+    let sessions = sqlx::query_as!(
+        RefreshSession,
+        r#"
+        SELECT id, device_label, user_agent, created_at, last_used_at, expires_at,
+            id IS NOT DISTINCT FROM $2 AS "current!"
+        FROM auth.refresh_tokens
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        ORDER BY last_used_at DESC
+        "#,
+        claims.user_id,
+        claims.sid
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(sessions))
+}
+
+#[instrument(level = "trace", skip(pool, claims))]
+async fn delete_session(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<StatusCode> {
+    let deleted = sqlx::query!(
+        "DELETE FROM auth.refresh_tokens WHERE id = $1 AND user_id = $2",
+        id,
+        claims.user_id
+    )
+    .execute(&pool)
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Err(Error::NotFound("Session not found".into()));
     }
-    error!(name: "exception_error", "No authentication header found");
-    Err(Error::from(AuthError::MissingCredentials))
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Logs the current device out. Tokens issued without `keep_login` have no refresh token to
+/// revoke, so this is a no-op for them.
+#[instrument(level = "trace", skip_all, fields(user_id = %claims.user_id))]
+async fn logout(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    jar: CookieJar,
+) -> Result<(StatusCode, CookieJar)> {
+    sqlx::query!(
+        "DELETE FROM auth.refresh_tokens WHERE id = $1 AND user_id = $2",
+        claims.sid,
+        claims.user_id
+    )
+    .execute(&pool)
+    .await?;
+    Ok((StatusCode::NO_CONTENT, cookies::cleared(jar)))
 }
 
-#[instrument(level = "trace", skip(req, next))]
+#[instrument(level = "trace", skip(pool, _admin))]
+async fn revoke_user_tokens(
+    State(pool): State<sqlx::PgPool>,
+    _admin: RequireAdmin,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<StatusCode> {
+    sqlx::query!("SELECT id FROM auth.users WHERE id = $1", id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| Error::NotFound("User not found".into()))?;
+    revoke_refresh_tokens(&mut *pool.acquire().await?, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Logs a user out of every device
+pub async fn revoke_refresh_tokens(
+    conn: &mut sqlx::PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<u64> {
+    revoke_other_refresh_tokens(conn, user_id, None).await
+}
+
+/// Logs a user out of every device except the one holding `keep`
+pub async fn revoke_other_refresh_tokens(
+    conn: &mut sqlx::PgConnection,
+    user_id: uuid::Uuid,
+    keep: Option<uuid::Uuid>,
+) -> Result<u64> {
+    let revoked = sqlx::query!(
+        "DELETE FROM auth.refresh_tokens WHERE user_id = $1 AND id IS DISTINCT FROM $2",
+        user_id,
+        keep
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(revoked.rows_affected())
+}
+
+#[instrument(level = "trace", skip(pool, header, req, next))]
 pub async fn mid_jwt_auth(
+    State(pool): State<sqlx::PgPool>,
     header: std::result::Result<TypedHeader<Authorization<Bearer>>, TypedHeaderRejection>,
     mut req: Request,
     next: Next,
 ) -> Result<Response> {
-    match header {
-        Ok(TypedHeader(Authorization(bearer))) => {
-            let token_data = decode::<AccessClaims>(
-                bearer.token(),
-                &ACCESS_KEYS.decoding,
-                &Validation::default(),
-            )
-            .map_err(|e| {
-                error!(name: "token_decoding_error", "Cannot decode token into claims: {}", e);
-                AuthError::InvalidToken
-            })?;
-            req.extensions_mut().insert(token_data.claims);
-            Ok(next.run(req).await)
+    let token = match header {
+        Ok(TypedHeader(Authorization(bearer)))
+            if bearer.token().starts_with(api_keys::KEY_PREFIX) =>
+        {
+            let (claims, scopes) = api_keys::authenticate(&pool, bearer.token()).await?;
+            req.extensions_mut().insert(claims);
+            req.extensions_mut().insert(scopes);
+            return Ok(next.run(req).await);
         }
+        Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
         Err(_) => {
-            error!(name: "exception_error", "Authorization header not found");
-            Err(Error::from(AuthError::MissingCredentials))
+            // browsers send the token as a cookie, which needs a CSRF check unlike a header
+            let jar = CookieJar::from_headers(req.headers());
+            let Some(cookie) = jar.get(cookies::ACCESS_COOKIE) else {
+                error!(name: "exception_error", "Authorization header not found");
+                return Err(Error::from(AuthError::MissingCredentials));
+            };
+            if !cookies::is_safe(req.method()) {
+                cookies::check_csrf(&jar, req.headers())?;
+            }
+            cookie.value().to_string()
         }
-    }
+    };
+    let token_data = ACCESS_KEYS.decode::<AccessClaims>(&token).map_err(|e| {
+        error!(name: "token_decoding_error", "Cannot decode token into claims: {}", e);
+        AuthError::InvalidToken
+    })?;
+    req.extensions_mut().insert(token_data.claims);
+    Ok(next.run(req).await)
 }
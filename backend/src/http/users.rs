@@ -1,34 +1,36 @@
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
-    Argon2,
-};
 use axum::http::StatusCode;
 use axum::{
     extract::{Json, Query, State},
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Router,
 };
+use chrono::{Duration, Utc};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::header;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::info;
 use validator::Validate;
 
 use crate::http::defaults::{default_time, default_uuid};
-use crate::{Error, Result};
+use crate::http::token::{self, AuthError};
+use crate::http::{
+    AppState, CurrentUser, Permission, RequirePermission, ViewMembers, ViewPayments,
+};
+use crate::mail::{outbox, templates};
+use crate::{password, Error, Result};
 
-static USERNAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[0-9A-Za-z_]+$").unwrap());
-static PASSWORD_REGEX: Lazy<Regex> =
+pub static PASSWORD_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^.(.*[A-Za-z0-9])(.*\d).+$").unwrap());
-static NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z]+$").unwrap());
+pub static NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z]+$").unwrap());
 
 #[derive(sqlx::FromRow, Debug, Validate, Deserialize, Serialize)]
 pub struct PendingUser {
-    #[serde(default = "default_uuid")]
+    #[serde(skip_deserializing, default = "default_uuid")]
     pub id: uuid::Uuid,
-    #[serde(default = "default_uuid")]
+    #[serde(skip_deserializing, default = "new_verification_token")]
     pub verification_token: uuid::Uuid,
     #[validate(length(min=1, max=20), regex(path = *NAME_REGEX))]
     pub first_name: String,
@@ -38,8 +40,10 @@ pub struct PendingUser {
     pub cid: String,
     #[validate(length(min=8, max=32), regex(path = *PASSWORD_REGEX))]
     pub password: String,
-    #[serde(default = "default_time")]
+    #[serde(skip_deserializing, default = "default_time")]
     pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_deserializing, default = "default_time")]
+    pub token_sent_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(sqlx::FromRow, Debug, Validate, Deserialize, Serialize)]
@@ -56,27 +60,120 @@ pub struct User {
     pub password: String,
     pub admin: bool,
     pub tier: i16,
-    pub jti: Option<uuid::Uuid>,
     #[serde(default = "default_time")]
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub last_login: Option<chrono::DateTime<chrono::Utc>>,
 }
 
-#[derive(Debug, Validate, Deserialize, Serialize)]
-pub struct UserAuth {
-    pub shortcode: String,
-    pub password: String,
-}
-
 #[derive(Deserialize)]
 pub struct VerificationToken {
     pub token: uuid::Uuid,
 }
 
-pub fn router() -> Router<sqlx::PgPool> {
+#[derive(Deserialize)]
+pub struct ResendRequest {
+    pub shortcode: String,
+}
+
+/// How long a verification email stays valid for
+const VERIFICATION_TTL_HOURS: i64 = 24;
+
+/// Minimum time between two verification emails to the same shortcode
+const RESEND_COOLDOWN_SECONDS: i64 = 300;
+
+/// Verification tokens are random rather than time-ordered so they cannot be guessed
+fn new_verification_token() -> uuid::Uuid {
+    uuid::Uuid::new_v4()
+}
+
+async fn queue_verification(
+    conn: &mut sqlx::PgConnection,
+    shortcode: &str,
+    first_name: &str,
+    verification_token: uuid::Uuid,
+) -> Result<()> {
+    let message = templates::verification(
+        shortcode,
+        first_name,
+        &verification_token.to_string(),
+        VERIFICATION_TTL_HOURS,
+    );
+    outbox::enqueue(conn, &message).await
+}
+
+/// Deletes registrations whose last verification email was sent over `days` days ago, freeing
+/// their shortcode and CID to register again
+pub async fn purge_pending_users(pool: &sqlx::PgPool, days: i64) -> Result<u64> {
+    let purged = sqlx::query!(
+        "DELETE FROM auth.pending_users WHERE token_sent_at < CURRENT_TIMESTAMP - make_interval(days => $1)",
+        days as i32
+    )
+    .execute(pool)
+    .await?;
+    if purged.rows_affected() > 0 {
+        info!("Purged {} unverified registrations", purged.rows_affected());
+    }
+    Ok(purged.rows_affected())
+}
+
+pub fn router() -> Router<AppState> {
     Router::new()
         .route("/register", post(PendingUser::create))
         .route("/verify", post(PendingUser::verify))
+        .route("/verify/resend", post(PendingUser::resend))
+}
+
+pub fn members_router() -> Router<AppState> {
+    Router::new().route("/", get(list_members))
+}
+
+pub fn me_router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_profile).patch(update_profile))
+        .route("/password", post(change_password))
+}
+
+/// A club member as synced from eActivities. The order number is payment data, so it is only
+/// included for those who can view payments.
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct MemberListing {
+    pub first_name: Option<String>,
+    pub surname: Option<String>,
+    pub cid: String,
+    pub email: Option<String>,
+    pub login: Option<String>,
+    pub member_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_no: Option<i32>,
+}
+
+/// A user as they see themselves, without their password hash
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct Profile {
+    pub id: uuid::Uuid,
+    pub first_name: String,
+    pub surname: String,
+    pub shortcode: String,
+    pub cid: String,
+    pub admin: bool,
+    pub tier: i16,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_login: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ProfileUpdate {
+    #[validate(length(min=1, max=20), regex(path = *NAME_REGEX))]
+    pub first_name: Option<String>,
+    #[validate(length(min=1, max=20), regex(path = *NAME_REGEX))]
+    pub surname: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct PasswordChange {
+    pub current_password: String,
+    #[validate(length(min=8, max=32), regex(path = *PASSWORD_REGEX))]
+    pub new_password: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -91,19 +188,9 @@ struct Member {
     member_type: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct TeamMember {
-    first_name: String,
-    surname: String,
-    cid: String,
-    email: String,
-    login: String,
-}
-
 async fn get_team_id(products: Value) -> Result<i64> {
     if let Some(p) = products.as_array() {
-        for (_, v) in p.iter().enumerate() {
+        for v in p.iter() {
             if let Some(name) = v.get("Name") {
                 if let Some(lower) = name.as_str() {
                     let lower = lower.to_lowercase();
@@ -119,34 +206,34 @@ async fn get_team_id(products: Value) -> Result<i64> {
                 }
             }
         }
-        return Err(Error::NotFoundTeamMembership(String::from(
+        Err(Error::NotFoundTeamMembership(String::from(
             "Cannot find product",
-        )));
+        )))
     } else {
-        return Err(Error::NotFoundTeamMembership(String::from("Invalid Json")));
+        Err(Error::NotFoundTeamMembership(String::from("Invalid Json")))
     }
 }
 
 async fn get_team_members(pool: &sqlx::PgPool, team_members: sqlx::types::JsonValue) -> Result<()> {
     if let Some(x) = team_members.as_array() {
         let team_firstname: Vec<String> = x
-            .into_iter()
+            .iter()
             .map(|member| String::from(member["Customer"]["FirstName"].as_str().unwrap()))
             .collect();
         let team_surname: Vec<String> = x
-            .into_iter()
+            .iter()
             .map(|member| String::from(member["Customer"]["Surname"].as_str().unwrap()))
             .collect();
         let team_cid: Vec<String> = x
-            .into_iter()
+            .iter()
             .map(|member| String::from(member["Customer"]["CID"].as_str().unwrap()))
             .collect();
         let team_email: Vec<String> = x
-            .into_iter()
+            .iter()
             .map(|member| String::from(member["Customer"]["Email"].as_str().unwrap()))
             .collect();
         let team_login: Vec<String> = x
-            .into_iter()
+            .iter()
             .map(|member| String::from(member["Customer"]["Login"].as_str().unwrap()))
             .collect();
 
@@ -204,7 +291,7 @@ pub async fn get_members(pool: &sqlx::PgPool) -> Result<()> {
         .execute(pool)
         .await?;
 
-    let _ = get_team_members(pool, team_members).await?;
+    get_team_members(pool, team_members).await?;
 
     sqlx::query!("TRUNCATE TABLE records.members")
         .execute(pool)
@@ -230,7 +317,7 @@ pub async fn get_members(pool: &sqlx::PgPool) -> Result<()> {
     Ok(())
 }
 
-async fn check_tier(pool: &sqlx::PgPool, cid: &str, shortcode: &str) -> Result<i16> {
+pub async fn check_tier(pool: &sqlx::PgPool, cid: &str, shortcode: &str) -> Result<i16> {
     let team = sqlx::query!(
         r#"
 		SELECT cid, login FROM records.team_members WHERE cid = $1 AND login = $2
@@ -263,56 +350,92 @@ async fn check_tier(pool: &sqlx::PgPool, cid: &str, shortcode: &str) -> Result<i
 }
 
 impl PendingUser {
+    /// Registers a user once they verify their college email. Their tier is looked up on
+    /// verification, so members go through the same check as everyone else.
     pub async fn create(
         State(pool): State<sqlx::PgPool>,
         Json(req): Json<PendingUser>,
     ) -> Result<Response> {
         req.validate()?;
 
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(req.password.as_bytes(), &salt)?
-            .to_string();
+        let password_hash = password::hash(req.password.clone()).await?;
 
-        let tier = check_tier(&pool, &req.cid, &req.shortcode).await?;
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO auth.pending_users(id, verification_token, first_name, surname, shortcode, cid, password, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            req.id,
+            req.verification_token,
+            req.first_name,
+            req.surname,
+            req.shortcode,
+            req.cid,
+            password_hash,
+            req.created_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        queue_verification(
+            &mut tx,
+            &req.shortcode,
+            &req.first_name,
+            req.verification_token,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(StatusCode::CREATED.into_response())
+    }
 
-        if tier != 0 {
-            sqlx::query!(
-                "INSERT INTO auth.users VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)",
-                req.id,
-                req.first_name,
-                req.surname,
-                req.shortcode,
-                req.cid,
-                password_hash,
-                false,
-                tier,
-                req.created_at
-            )
-            .execute(&pool)
-            .await?;
-            Ok(StatusCode::CREATED.into_response())
-        } else {
-            sqlx::query!(
-				r#"
-				INSERT INTO auth.pending_users(id, verification_token, first_name, surname, shortcode, cid, password, created_at)
-				VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-				"#,
-				req.id,
-				req.verification_token,
-				req.first_name,
-				req.surname,
-				req.shortcode,
-				req.cid,
-				password_hash,
-				req.created_at
-			)
-			.execute(&pool)
-			.await?;
-            // NEED TO IMPLEMENT EMAIL VERIFICATION
-            Ok((StatusCode::CREATED, req.verification_token.to_string()).into_response())
+    /// Issues a new verification token and queues it to be emailed again. Limited to one email per
+    /// `RESEND_COOLDOWN_SECONDS` so the endpoint cannot be used to flood an inbox. The response is
+    /// the same whatever the shortcode, so it does not give away who has registered.
+    pub async fn resend(
+        State(pool): State<sqlx::PgPool>,
+        Json(req): Json<ResendRequest>,
+    ) -> Result<StatusCode> {
+        let mut tx = pool.begin().await?;
+        let pending_user = sqlx::query!(
+            "SELECT first_name, token_sent_at FROM auth.pending_users WHERE shortcode = $1 FOR UPDATE",
+            req.shortcode
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(pending_user) = pending_user else {
+            info!("Verification resend requested for unknown shortcode");
+            return Ok(StatusCode::ACCEPTED);
+        };
+
+        if Utc::now() < pending_user.token_sent_at + Duration::seconds(RESEND_COOLDOWN_SECONDS) {
+            info!(
+                "Verification resend for {} requested again within the cooldown",
+                req.shortcode
+            );
+            return Ok(StatusCode::ACCEPTED);
         }
+
+        let verification_token = new_verification_token();
+        sqlx::query!(
+            r#"
+            UPDATE auth.pending_users SET verification_token = $1, token_sent_at = CURRENT_TIMESTAMP
+            WHERE shortcode = $2
+            "#,
+            verification_token,
+            req.shortcode
+        )
+        .execute(&mut *tx)
+        .await?;
+        queue_verification(
+            &mut tx,
+            &req.shortcode,
+            &pending_user.first_name,
+            verification_token,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(StatusCode::ACCEPTED)
     }
 
     pub async fn verify(
@@ -330,6 +453,11 @@ impl PendingUser {
         .await?;
 
         if let Some(x) = pending_user {
+            if x.token_sent_at + Duration::hours(VERIFICATION_TTL_HOURS) < Utc::now() {
+                return Err(Error::UnprocessableEntity(
+                    "Token has expired, request a new one".into(),
+                ));
+            }
             let tier = check_tier(&pool, &x.cid, &x.shortcode).await?;
             sqlx::query!(
                 r#"
@@ -354,3 +482,109 @@ impl PendingUser {
         Err(Error::UnprocessableEntity("Invalid Token".into()))
     }
 }
+
+async fn get_profile(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+) -> Result<Json<Profile>> {
+    let profile = sqlx::query_as!(
+        Profile,
+        r#"
+        SELECT id, first_name, surname, shortcode, cid, admin, tier, created_at, last_login
+        FROM auth.users WHERE id = $1
+        "#,
+        claims.user_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("User not found".into()))?;
+
+    Ok(Json(profile))
+}
+
+/// Updates the user's name. Access tokens carry the name, so the change shows up in tokens
+/// issued from the next login or refresh onwards.
+async fn update_profile(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Json(payload): Json<ProfileUpdate>,
+) -> Result<Json<Profile>> {
+    payload.validate()?;
+
+    let profile = sqlx::query_as!(
+        Profile,
+        r#"
+        UPDATE auth.users
+        SET first_name = COALESCE($2, first_name), surname = COALESCE($3, surname)
+        WHERE id = $1
+        RETURNING id, first_name, surname, shortcode, cid, admin, tier, created_at, last_login
+        "#,
+        claims.user_id,
+        payload.first_name,
+        payload.surname
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("User not found".into()))?;
+
+    Ok(Json(profile))
+}
+
+/// Changes the password after checking the current one. Every other device is logged out, while
+/// the device making the change keeps its refresh token.
+async fn change_password(
+    State(pool): State<sqlx::PgPool>,
+    CurrentUser(claims): CurrentUser,
+    Json(payload): Json<PasswordChange>,
+) -> Result<StatusCode> {
+    payload.validate()?;
+
+    let mut tx = pool.begin().await?;
+    let current_hash = sqlx::query_scalar!(
+        "SELECT password FROM auth.users WHERE id = $1 FOR UPDATE",
+        claims.user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::NotFound("User not found".into()))?;
+
+    if !password::verify(payload.current_password, current_hash).await? {
+        return Err(Error::from(AuthError::WrongCredentials));
+    }
+
+    let password_hash = password::hash(payload.new_password).await?;
+    sqlx::query!(
+        "UPDATE auth.users SET password = $1 WHERE id = $2",
+        password_hash,
+        claims.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    token::revoke_other_refresh_tokens(&mut tx, claims.user_id, claims.sid).await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_members(
+    State(pool): State<sqlx::PgPool>,
+    RequirePermission(claims, _): RequirePermission<ViewMembers>,
+) -> Result<Json<Vec<MemberListing>>> {
+    let mut members = sqlx::query_as!(
+        MemberListing,
+        r#"
+        SELECT first_name, surname, cid, email, login, member_type, order_no
+        FROM records.members
+        ORDER BY surname, first_name
+        "#
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    if !claims.has_permission(ViewPayments::NAME) {
+        for member in &mut members {
+            member.order_no = None;
+        }
+    }
+    Ok(Json(members))
+}
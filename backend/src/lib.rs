@@ -1,4 +1,6 @@
 pub mod http;
 pub mod error;
+pub mod mail;
+pub mod password;
 
 pub use self::error::{Error, Result};
\ No newline at end of file
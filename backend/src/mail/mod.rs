@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use tracing::info;
+
+use crate::Result;
+
+pub mod outbox;
+pub mod templates;
+
+/// An email with a plain-text body and optionally an HTML alternative
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, message: Message) -> Result<()>;
+}
+
+/// Writes emails to the log instead of sending them, for development
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, message: Message) -> Result<()> {
+        info!(name: "mail", to = %message.to, subject = %message.subject, "{}", message.text);
+        Ok(())
+    }
+}
+
+/// Sends emails through an SMTP relay
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn from_env() -> Result<Self> {
+        let host = dotenvy::var("SMTP_HOST")?;
+        let username = dotenvy::var("SMTP_USERNAME")?;
+        let password = dotenvy::var("SMTP_PASSWORD")?;
+        let from = dotenvy::var("MAIL_FROM")?.parse()?;
+
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?
+            .credentials(Credentials::new(username, password))
+            .build();
+        Ok(Self { transport, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, message: Message) -> Result<()> {
+        let builder = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(message.to.parse()?)
+            .subject(message.subject);
+        let email = match message.html {
+            Some(html) => {
+                builder.multipart(MultiPart::alternative_plain_html(message.text, html))?
+            }
+            None => builder.body(message.text)?,
+        };
+        self.transport.send(email).await?;
+        Ok(())
+    }
+}
+
+/// Writes each email to its own file in a directory, with any HTML version alongside it, for
+/// staging environments where nothing should leave the server
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, message: Message) -> Result<()> {
+        let name = format!(
+            "{}-{}.txt",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            uuid::Uuid::new_v4()
+        );
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            message.to, message.subject, message.text
+        );
+        tokio::fs::write(self.dir.join(&name), contents).await?;
+        if let Some(html) = message.html {
+            tokio::fs::write(self.dir.join(name).with_extension("html"), html).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps sent emails in memory so tests can inspect them
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Message>>,
+}
+
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Message> {
+        self.sent.lock().expect("mailer lock poisoned").clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, message: Message) -> Result<()> {
+        self.sent
+            .lock()
+            .expect("mailer lock poisoned")
+            .push(message);
+        Ok(())
+    }
+}
+
+/// College email address of a user, which is where all mail to them is sent
+pub fn college_address(shortcode: &str) -> String {
+    format!("{}@ic.ac.uk", shortcode)
+}
+
+/// Picks the transport named by `MAIL_TRANSPORT`: `smtp`, `file` (into `MAIL_DIR`) or `log`
+pub fn from_env() -> Result<Arc<dyn Mailer>> {
+    let transport = dotenvy::var("MAIL_TRANSPORT").unwrap_or_else(|_| "log".into());
+    let mailer: Arc<dyn Mailer> = match transport.as_str() {
+        "smtp" => Arc::new(SmtpMailer::from_env()?),
+        "file" => Arc::new(FileMailer::new(dotenvy::var("MAIL_DIR")?)?),
+        _ => Arc::new(LogMailer),
+    };
+    info!("Sending mail with the {} transport", transport);
+    Ok(mailer)
+}
@@ -0,0 +1,165 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::mail::{Mailer, Message};
+use crate::Result;
+
+/// Sends are given up on and marked as failed after this many attempts
+const MAX_ATTEMPTS: i32 = 8;
+
+/// Delay before the first retry, doubling with every further attempt
+const RETRY_BASE_SECONDS: i64 = 30;
+
+/// Upper bound on the delay between two attempts
+const RETRY_MAX_SECONDS: i64 = 3600;
+
+/// How many emails a single delivery run picks up
+const BATCH_SIZE: usize = 20;
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct OutboxEntry {
+    pub id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// An email the outbox gave up on, without its body
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct FailedEmail {
+    pub id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
+/// Queues an email as part of the caller's transaction, so it is only sent if that transaction
+/// commits
+pub async fn enqueue(conn: &mut sqlx::PgConnection, message: &Message) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO records.mail_outbox(recipient, subject, text_body, html_body)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        message.to,
+        message.subject,
+        message.text,
+        message.html
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+fn retry_delay(attempts: i32) -> Duration {
+    let seconds = RETRY_BASE_SECONDS
+        .saturating_mul(1 << (attempts - 1).clamp(0, 16))
+        .min(RETRY_MAX_SECONDS);
+    Duration::seconds(seconds)
+}
+
+/// Sends the queued emails that are due, up to `BATCH_SIZE`. Bodies can carry one-time tokens,
+/// so they are cleared once an email is sent or given up on. Each email is claimed with
+/// `SKIP LOCKED` in a transaction of its own, committed as soon as it has been sent, so several
+/// workers can run side by side and a failure part way through never causes a resend of the
+/// emails already delivered. Returns how many were sent.
+pub async fn deliver_due(pool: &sqlx::PgPool, mailer: &dyn Mailer) -> Result<usize> {
+    let mut sent = 0;
+    for _ in 0..BATCH_SIZE {
+        let mut tx = pool.begin().await?;
+        let entry = sqlx::query_as!(
+            OutboxEntry,
+            r#"
+            SELECT * FROM records.mail_outbox
+            WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+            ORDER BY next_attempt_at
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+            "#
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(entry) = entry else {
+            break;
+        };
+
+        let message = Message {
+            to: entry.recipient,
+            subject: entry.subject,
+            text: entry.text_body,
+            html: entry.html_body,
+        };
+        match mailer.send(message).await {
+            Ok(()) => {
+                sqlx::query!(
+                    r#"
+                    UPDATE records.mail_outbox
+                    SET status = 'sent', attempts = attempts + 1, sent_at = CURRENT_TIMESTAMP,
+                        last_error = NULL, text_body = '', html_body = NULL
+                    WHERE id = $1
+                    "#,
+                    entry.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                sent += 1;
+            }
+            Err(e) => {
+                let attempts = entry.attempts + 1;
+                let status = if attempts >= MAX_ATTEMPTS {
+                    "failed"
+                } else {
+                    "pending"
+                };
+                warn!(name: "mail_error", "Attempt {} to send email {} failed: {:?}", attempts, entry.id, e);
+                sqlx::query!(
+                    r#"
+                    UPDATE records.mail_outbox
+                    SET status = $2, attempts = $3, next_attempt_at = $4, last_error = $5,
+                        text_body = CASE WHEN $2 = 'failed' THEN '' ELSE text_body END,
+                        html_body = CASE WHEN $2 = 'failed' THEN NULL ELSE html_body END
+                    WHERE id = $1
+                    "#,
+                    entry.id,
+                    status,
+                    attempts,
+                    Utc::now() + retry_delay(attempts),
+                    format!("{:?}", e)
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+    }
+
+    if sent > 0 {
+        info!("Sent {} queued emails", sent);
+    }
+    Ok(sent)
+}
+
+/// Emails that ran out of attempts, most recent first
+pub async fn failed(pool: &sqlx::PgPool) -> Result<Vec<FailedEmail>> {
+    let entries = sqlx::query_as!(
+        FailedEmail,
+        r#"
+        SELECT id, recipient, subject, attempts, last_error FROM records.mail_outbox
+        WHERE status = 'failed'
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(entries)
+}
@@ -0,0 +1,184 @@
+use chrono::{DateTime, Utc};
+
+use crate::mail::{college_address, Message};
+
+/// Times in emails are shown in the timezone sessions are scheduled in
+const DISPLAY_TZ: chrono_tz::Tz = chrono_tz::Europe::London;
+
+const LAYOUT: &str = include_str!("../../templates/mail/layout.html");
+
+/// Plain-text and HTML versions of the same email
+struct Template {
+    text: &'static str,
+    html: &'static str,
+}
+
+macro_rules! template {
+    ($name:literal) => {
+        Template {
+            text: include_str!(concat!("../../templates/mail/", $name, ".txt")),
+            html: include_str!(concat!("../../templates/mail/", $name, ".html")),
+        }
+    };
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces each `{{key}}` in the template with its value in a single pass, so placeholders
+/// that turn up inside a value are left as they are. Unknown placeholders are kept.
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    let mut body = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        body.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let key = &after[..end];
+            let (_, value) = vars.iter().find(|(name, _)| *name == key)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                body.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                body.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    body.push_str(rest);
+    body
+}
+
+fn render(template: Template, shortcode: &str, subject: &str, vars: &[(&str, String)]) -> Message {
+    let escaped: Vec<(&str, String)> = vars
+        .iter()
+        .map(|(key, value)| (*key, escape_html(value)))
+        .collect();
+    let content = fill(template.html, &escaped);
+    let html = fill(
+        LAYOUT,
+        &[("subject", escape_html(subject)), ("content", content)],
+    );
+
+    Message {
+        to: college_address(shortcode),
+        subject: subject.to_string(),
+        text: fill(template.text, vars),
+        html: Some(html),
+    }
+}
+
+fn display_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&DISPLAY_TZ)
+        .format("%A %-d %B %Y, %H:%M")
+        .to_string()
+}
+
+pub fn verification(shortcode: &str, first_name: &str, token: &str, hours: i64) -> Message {
+    render(
+        template!("verification"),
+        shortcode,
+        "Verify your account",
+        &[
+            ("first_name", first_name.to_string()),
+            ("token", token.to_string()),
+            ("hours", hours.to_string()),
+        ],
+    )
+}
+
+pub fn password_reset(shortcode: &str, first_name: &str, token: &str, minutes: i64) -> Message {
+    render(
+        template!("password_reset"),
+        shortcode,
+        "Reset your password",
+        &[
+            ("first_name", first_name.to_string()),
+            ("token", token.to_string()),
+            ("minutes", minutes.to_string()),
+        ],
+    )
+}
+
+pub fn booking_confirmed(
+    shortcode: &str,
+    first_name: &str,
+    title: &str,
+    location: &str,
+    start_time: DateTime<Utc>,
+) -> Message {
+    render(
+        template!("booking_confirmed"),
+        shortcode,
+        &format!("Booking confirmed: {}", title),
+        &[
+            ("first_name", first_name.to_string()),
+            ("title", title.to_string()),
+            ("location", location.to_string()),
+            ("start_time", display_time(start_time)),
+        ],
+    )
+}
+
+pub fn waitlist_promoted(
+    shortcode: &str,
+    first_name: &str,
+    title: &str,
+    location: &str,
+    start_time: DateTime<Utc>,
+) -> Message {
+    render(
+        template!("waitlist_promoted"),
+        shortcode,
+        &format!("You're off the waitlist: {}", title),
+        &[
+            ("first_name", first_name.to_string()),
+            ("title", title.to_string()),
+            ("location", location.to_string()),
+            ("start_time", display_time(start_time)),
+        ],
+    )
+}
+
+pub fn booking_released(
+    shortcode: &str,
+    first_name: &str,
+    title: &str,
+    location: &str,
+    start_time: DateTime<Utc>,
+    cancelled: bool,
+) -> Message {
+    let reason = if cancelled {
+        "this date has been cancelled"
+    } else {
+        "the number of places has been reduced"
+    };
+    render(
+        template!("booking_released"),
+        shortcode,
+        &format!("Booking released: {}", title),
+        &[
+            ("first_name", first_name.to_string()),
+            ("title", title.to_string()),
+            ("location", location.to_string()),
+            ("start_time", display_time(start_time)),
+            ("reason", reason.to_string()),
+        ],
+    )
+}
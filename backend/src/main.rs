@@ -1,5 +1,8 @@
 use backend::Result;
 use sqlx::postgres::PgPoolOptions;
+use std::time::Duration;
+use tokio::time;
+use tracing::error;
 use tracing_subscriber::fmt;
 
 #[tokio::main]
@@ -25,5 +28,49 @@ async fn main() -> Result<()> {
     //    }
     //});
 
-    backend::http::serve(pool).await
+    let ballot_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = backend::http::draw_due_ballots(&ballot_pool).await {
+                error!(name: "ballot_error", "Cannot draw due ballots: {}", e);
+            }
+        }
+    });
+
+    let retention_days = dotenvy::var("PENDING_USER_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(7);
+    let purge_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            if let Err(e) = backend::http::purge_pending_users(&purge_pool, retention_days).await {
+                error!(name: "purge_error", "Cannot purge pending users: {}", e);
+            }
+            if let Err(e) = backend::http::purge_login_throttles(&purge_pool).await {
+                error!(name: "purge_error", "Cannot purge login throttles: {}", e);
+            }
+        }
+    });
+
+    let mailer = backend::mail::from_env()?;
+    let outbox_pool = pool.clone();
+    let outbox_mailer = mailer.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            if let Err(e) = backend::mail::outbox::deliver_due(&outbox_pool, &*outbox_mailer).await
+            {
+                error!(name: "mail_error", "Cannot deliver queued emails: {}", e);
+            }
+        }
+    });
+
+    let state = backend::http::AppState { db: pool, mailer };
+    backend::http::serve(state).await
 }
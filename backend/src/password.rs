@@ -0,0 +1,94 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use once_cell::sync::Lazy;
+use tracing::warn;
+
+use crate::Result;
+
+/// Argon2id cost, set with `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS` and `ARGON2_PARALLELISM`.
+/// Unset values keep the argon2 crate's defaults.
+static PARAMS: Lazy<Params> = Lazy::new(|| {
+    Params::new(
+        env_cost("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+        env_cost("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+        env_cost("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+        None,
+    )
+    .expect("Argon2 parameters must be within the limits of the algorithm")
+});
+
+/// Hash of a random password made with the configured parameters, see `verify_dummy`
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    let password: [u8; 32] = rand::random();
+    argon2()
+        .hash_password(&password, &salt)
+        .expect("Hashing a random password cannot fail")
+        .to_string()
+});
+
+fn env_cost(var: &str, default: u32) -> u32 {
+    match dotenvy::var(var) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a positive integer", var)),
+        Err(_) => default,
+    }
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, PARAMS.clone())
+}
+
+/// Hashes a password with the configured parameters, off the async runtime as it is slow on
+/// purpose
+pub async fn hash(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(argon2()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    })
+    .await?
+}
+
+/// Checks a password against a stored hash, whatever parameters it was made with
+pub async fn verify(password: String, hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let parsed_hash = PasswordHash::new(&hash)?;
+        Ok(argon2()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok())
+    })
+    .await?
+}
+
+/// Takes as long as `verify` against a real hash, for when there is no account to check the
+/// password of, so that unknown shortcodes can't be told apart by how fast they fail
+pub async fn verify_dummy(password: String) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let parsed_hash = PasswordHash::new(&DUMMY_HASH)?;
+        let _ = argon2().verify_password(password.as_bytes(), &parsed_hash);
+        Ok(())
+    })
+    .await?
+}
+
+/// Whether a stored hash was made with another algorithm or parameters than the ones
+/// configured now, so should be replaced the next time we see the password
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hash) else {
+        warn!("Stored password hash cannot be parsed");
+        return false;
+    };
+    let Ok(params) = Params::try_from(&parsed_hash) else {
+        return true;
+    };
+    parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != PARAMS.m_cost()
+        || params.t_cost() != PARAMS.t_cost()
+        || params.p_cost() != PARAMS.p_cost()
+}
@@ -0,0 +1,228 @@
+mod common;
+
+use std::sync::Once;
+
+use axum::body::Body;
+use axum::http::{header, Request};
+use common::{TestApp, TestResponse};
+use serde_json::{json, Value};
+
+/// The app, with token secrets set before anything reads them
+fn app(pool: sqlx::PgPool) -> TestApp {
+    static SECRETS: Once = Once::new();
+    SECRETS.call_once(|| {
+        std::env::set_var("ACCESS_JWT_SECRET", "test-access-secret");
+        std::env::set_var("REFRESH_JWT_SECRET", "test-refresh-secret");
+    });
+    TestApp::new(pool)
+}
+
+/// Creates a member who can log in with `Password123`
+async fn insert_user(pool: &sqlx::PgPool, shortcode: &str, cid: &str, admin: bool) {
+    let password = backend::password::hash("Password123".into()).await.unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO auth.users(first_name, surname, shortcode, cid, password, admin, tier)
+        VALUES ('Ada', 'Lovelace', $1, $2, $3, $4, 1)
+        "#,
+        shortcode,
+        cid,
+        password,
+        admin
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn login(app: &TestApp, shortcode: &str) -> String {
+    let response = app
+        .post(
+            "/api/v1/users/login",
+            json!({ "shortcode": shortcode, "password": "Password123", "keep_login": false }),
+        )
+        .await;
+    assert_eq!(response.status, 200);
+    response.body["access_token"].as_str().unwrap().to_string()
+}
+
+async fn authed(app: &TestApp, method: &str, uri: &str, token: &str, body: Value) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.send(request).await
+}
+
+async fn book(app: &TestApp, uri: &str, token: &str) -> TestResponse {
+    authed(app, "POST", uri, token, json!(null)).await
+}
+
+/// A one-off session next week with room for `user_limit`, allocated by `allocation`
+async fn create_session(app: &TestApp, user_limit: i16, allocation: &str) -> String {
+    insert_user(&app.pool, "admin1", "09999999", true).await;
+    let token = login(app, "admin1").await;
+    let start_time = chrono::Utc::now() + chrono::Duration::days(7);
+    let response = authed(
+        app,
+        "POST",
+        "/api/v1/sessions",
+        &token,
+        json!({
+            "title": "Club night",
+            "description": "",
+            "location": "Hall",
+            "tier": 0,
+            "start_time": start_time,
+            "end_time": start_time + chrono::Duration::hours(2),
+            "user_limit": user_limit,
+            "allocation": allocation,
+        }),
+    )
+    .await;
+    assert_eq!(response.status, 201);
+    response.body["id"].as_str().unwrap().to_string()
+}
+
+async fn booked(pool: &sqlx::PgPool) -> Vec<String> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT u.shortcode FROM records.bookings b JOIN auth.users u ON u.id = b.user_id
+        ORDER BY u.shortcode
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test]
+async fn simultaneous_bookings_never_exceed_the_limit(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let id = create_session(&app, 2, "first_come").await;
+
+    let mut tokens = Vec::new();
+    for n in 0..5 {
+        let shortcode = format!("bk{n}");
+        insert_user(&app.pool, &shortcode, &format!("0200000{n}"), false).await;
+        tokens.push(login(&app, &shortcode).await);
+    }
+
+    let uri = format!("/api/v1/sessions/{id}/book");
+    let responses = tokio::join!(
+        book(&app, &uri, &tokens[0]),
+        book(&app, &uri, &tokens[1]),
+        book(&app, &uri, &tokens[2]),
+        book(&app, &uri, &tokens[3]),
+        book(&app, &uri, &tokens[4])
+    );
+    let statuses = [
+        responses.0.status,
+        responses.1.status,
+        responses.2.status,
+        responses.3.status,
+        responses.4.status,
+    ];
+
+    let created = statuses.iter().filter(|s| **s == 201).count();
+    let waitlisted = statuses.iter().filter(|s| **s == 202).count();
+    assert_eq!((created, waitlisted), (2, 3));
+    assert_eq!(booked(&app.pool).await.len(), 2);
+}
+
+#[sqlx::test]
+async fn cancelling_promotes_the_earliest_waitlisted_user(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let id = create_session(&app, 1, "first_come").await;
+    let uri = format!("/api/v1/sessions/{id}/book");
+
+    let mut tokens = Vec::new();
+    for (n, shortcode) in ["first", "second", "third"].into_iter().enumerate() {
+        insert_user(&app.pool, shortcode, &format!("0300000{n}"), false).await;
+        let token = login(&app, shortcode).await;
+        let expected = if n == 0 { 201 } else { 202 };
+        assert_eq!(book(&app, &uri, &token).await.status, expected);
+        tokens.push(token);
+    }
+    let sent = app.deliver_mail().await.len();
+
+    let response = authed(&app, "DELETE", &uri, &tokens[0], json!(null)).await;
+    assert_eq!(response.status, 200);
+    assert_eq!(booked(&app.pool).await, vec!["second"]);
+
+    let mail = app.deliver_mail().await;
+    let promoted: Vec<_> = mail[sent..].iter().collect();
+    assert_eq!(promoted.len(), 1);
+    assert_eq!(promoted[0].to, "second@ic.ac.uk");
+    assert!(promoted[0].subject.starts_with("You're off the waitlist"));
+
+    let response = authed(
+        &app,
+        "GET",
+        &format!("/api/v1/sessions/{id}/waitlist"),
+        &tokens[2],
+        json!(null),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["position"], 1);
+}
+
+#[sqlx::test]
+async fn stored_draws_can_be_reproduced(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let id = create_session(&app, 2, "ballot").await;
+    let uri = format!("/api/v1/sessions/{id}/book");
+
+    let mut tokens = Vec::new();
+    for n in 0..4 {
+        let shortcode = format!("bl{n}");
+        insert_user(&app.pool, &shortcode, &format!("0400000{n}"), false).await;
+        let token = login(&app, &shortcode).await;
+        assert_eq!(book(&app, &uri, &token).await.status, 202);
+        tokens.push(token);
+    }
+
+    // close entries by moving the cutoff back past now
+    sqlx::query!("UPDATE records.session_forms SET ballot_cutoff = interval '8 days'")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    backend::http::draw_due_ballots(&app.pool).await.unwrap();
+
+    let occurrence = sqlx::query_scalar!("SELECT id FROM records.session_occurrences")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    let response = authed(
+        &app,
+        "GET",
+        &format!("/api/v1/sessions/{id}/occurrences/{occurrence}/draw"),
+        &tokens[0],
+        json!(null),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["allocated"].as_array().unwrap().len(), 2);
+    assert_eq!(response.body["waitlisted"].as_array().unwrap().len(), 2);
+    assert_eq!(response.body["reproduced"], true);
+
+    // the draw names every entrant, so non-members cannot see it
+    sqlx::query!("UPDATE auth.users SET tier = 0 WHERE shortcode = 'bl1'")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let token = login(&app, "bl1").await;
+    let response = authed(
+        &app,
+        "GET",
+        &format!("/api/v1/sessions/{id}/occurrences/{occurrence}/draw"),
+        &token,
+        json!(null),
+    )
+    .await;
+    assert_eq!(response.status, 403);
+}
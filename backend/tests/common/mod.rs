@@ -0,0 +1,73 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use backend::http::{router_app, AppState};
+use backend::mail::{outbox, MemoryMailer, Message};
+use serde_json::Value;
+use tower::Service;
+
+/// The app on a test database, with mail kept in memory
+pub struct TestApp {
+    pub pool: sqlx::PgPool,
+    pub mailer: Arc<MemoryMailer>,
+    router: Router,
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: header::HeaderMap,
+    pub body: Value,
+}
+
+impl TestApp {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        let mailer = Arc::new(MemoryMailer::default());
+        let router = router_app(AppState {
+            db: pool.clone(),
+            mailer: mailer.clone(),
+        });
+        Self {
+            pool,
+            mailer,
+            router,
+        }
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> TestResponse {
+        let response = self.router.clone().call(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        TestResponse {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub async fn post(&self, uri: &str, body: Value) -> TestResponse {
+        let request = Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.send(request).await
+    }
+
+    pub async fn get(&self, uri: &str) -> TestResponse {
+        self.send(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+    }
+
+    /// Runs the outbox once and returns everything sent so far
+    pub async fn deliver_mail(&self) -> Vec<Message> {
+        outbox::deliver_due(&self.pool, &*self.mailer)
+            .await
+            .unwrap();
+        self.mailer.sent()
+    }
+}
@@ -0,0 +1,383 @@
+mod common;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use axum::body::Body;
+use axum::extract::{Form, State};
+use axum::http::{header, Request, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::{TestApp, TestResponse};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+const CLIENT_ID: &str = "icsm-test";
+
+/// What the provider remembers about a code between the login page and the token request
+struct Grant {
+    code_challenge: String,
+    nonce: String,
+    claims: Value,
+}
+
+/// A provider with discovery, token and JWKS endpoints, signing ID tokens with Ed25519
+struct StubIssuer {
+    issuer: String,
+    key: EncodingKey,
+    jwks: Value,
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+/// One provider for the whole test binary, as the app reads `OIDC_*` once. It runs on a
+/// runtime of its own, since each test gets a new one.
+fn stub() -> Arc<StubIssuer> {
+    static STUB: OnceLock<Arc<StubIssuer>> = OnceLock::new();
+    STUB.get_or_init(|| {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let stub = Arc::new(StubIssuer {
+            issuer: issuer.clone(),
+            key: EncodingKey::from_ed_der(pkcs8.as_ref()),
+            jwks: json!({ "keys": [{
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "kid": "stub",
+                "alg": "EdDSA",
+                "use": "sig",
+                "x": URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            }] }),
+            grants: Mutex::new(HashMap::new()),
+        });
+
+        std::env::set_var("OIDC_ISSUER", &issuer);
+        std::env::set_var("OIDC_CLIENT_ID", CLIENT_ID);
+        std::env::set_var(
+            "OIDC_REDIRECT_URI",
+            "https://icsm.test/api/v1/users/oidc/callback",
+        );
+        std::env::set_var("ACCESS_JWT_SECRET", "test-access-secret");
+        std::env::set_var("REFRESH_JWT_SECRET", "test-refresh-secret");
+
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(stub.clone());
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, router).await.unwrap();
+            })
+        });
+        stub
+    })
+    .clone()
+}
+
+async fn discovery(State(stub): State<Arc<StubIssuer>>) -> Json<Value> {
+    Json(json!({
+        "issuer": stub.issuer,
+        "authorization_endpoint": format!("{}/authorize", stub.issuer),
+        "token_endpoint": format!("{}/token", stub.issuer),
+        "jwks_uri": format!("{}/jwks", stub.issuer),
+    }))
+}
+
+async fn jwks(State(stub): State<Arc<StubIssuer>>) -> Json<Value> {
+    Json(stub.jwks.clone())
+}
+
+fn challenge_for(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Only hands out an ID token for a known code along with the verifier behind its challenge
+async fn token(
+    State(stub): State<Arc<StubIssuer>>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let invalid_grant = || {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+    };
+    let grant = stub
+        .grants
+        .lock()
+        .unwrap()
+        .remove(form.get("code").ok_or_else(invalid_grant)?)
+        .ok_or_else(invalid_grant)?;
+    let verifier = form.get("code_verifier").ok_or_else(invalid_grant)?;
+    if challenge_for(verifier) != grant.code_challenge {
+        return Err(invalid_grant());
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut claims = grant.claims;
+    claims["iss"] = json!(stub.issuer);
+    claims["aud"] = json!(CLIENT_ID);
+    claims["nonce"] = json!(grant.nonce);
+    claims["iat"] = json!(now);
+    claims["exp"] = json!(now + 300);
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some("stub".into());
+    let id_token = jsonwebtoken::encode(&header, &claims, &stub.key).unwrap();
+    Ok(Json(
+        json!({ "id_token": id_token, "token_type": "Bearer" }),
+    ))
+}
+
+/// A login started with the app, as the browser is sent to the provider
+struct Login {
+    state: String,
+    nonce: String,
+    code_challenge: String,
+    cookie: String,
+}
+
+impl Login {
+    async fn start(app: &TestApp) -> Login {
+        let response = app.get("/api/v1/users/oidc/authorize").await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+
+        let location = response.headers[header::LOCATION].to_str().unwrap();
+        let url = reqwest::Url::parse(location).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        let cookie = response
+            .headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find(|value| value.starts_with("oidc_state="))
+            .expect("no state cookie");
+        assert!(cookie.contains("HttpOnly") && cookie.contains("SameSite=Lax"));
+
+        Login {
+            state: query["state"].clone(),
+            nonce: query["nonce"].clone(),
+            code_challenge: query["code_challenge"].clone(),
+            cookie: cookie.split(';').next().unwrap().to_string(),
+        }
+    }
+
+    /// The user logs in at the provider, which sends them back with this code
+    fn grant(&self, claims: Value) -> String {
+        self.grant_with_challenge(claims, &self.code_challenge)
+    }
+
+    fn grant_with_challenge(&self, claims: Value, code_challenge: &str) -> String {
+        let code = uuid::Uuid::new_v4().to_string();
+        stub().grants.lock().unwrap().insert(
+            code.clone(),
+            Grant {
+                code_challenge: code_challenge.to_string(),
+                nonce: self.nonce.clone(),
+                claims,
+            },
+        );
+        code
+    }
+
+    async fn callback(&self, app: &TestApp, code: &str, cookie: Option<&str>) -> TestResponse {
+        let mut request = Request::get(format!(
+            "/api/v1/users/oidc/callback?state={}&code={}",
+            self.state, code
+        ));
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        app.send(request.body(Body::empty()).unwrap()).await
+    }
+
+    async fn finish(&self, app: &TestApp, code: &str) -> TestResponse {
+        self.callback(app, code, Some(&self.cookie)).await
+    }
+}
+
+/// The app, with the provider up and configured before anything reads the configuration
+fn app(pool: sqlx::PgPool) -> TestApp {
+    stub();
+    TestApp::new(pool)
+}
+
+fn id_claims(sub: &str, shortcode: &str, cid: &str) -> Value {
+    json!({
+        "sub": sub,
+        "preferred_username": format!("{shortcode}@ic.ac.uk"),
+        "cid": cid,
+        "given_name": "Ada",
+        "family_name": "Lovelace",
+    })
+}
+
+async fn user_ids(pool: &sqlx::PgPool, shortcode: &str) -> Vec<uuid::Uuid> {
+    sqlx::query_scalar!("SELECT id FROM auth.users WHERE shortcode = $1", shortcode)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+async fn linked_user(pool: &sqlx::PgPool, subject: &str) -> Option<uuid::Uuid> {
+    sqlx::query_scalar!(
+        "SELECT user_id FROM auth.user_identities WHERE subject = $1",
+        subject
+    )
+    .fetch_optional(pool)
+    .await
+    .unwrap()
+}
+
+async fn insert_user(pool: &sqlx::PgPool, shortcode: &str, cid: &str) -> uuid::Uuid {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO auth.users(first_name, surname, shortcode, cid, password, admin, tier)
+        VALUES ('Grace', 'Hopper', $1, $2, 'x', false, 0)
+        RETURNING id
+        "#,
+        shortcode,
+        cid
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test]
+async fn first_login_creates_an_account_with_the_pkce_verifier(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let login = Login::start(&app).await;
+    let code = login.grant(id_claims("sub-ada", "al123", "01234567"));
+
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body["access_token"].is_string());
+    let cleared = response
+        .headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.starts_with("oidc_state=;"));
+    assert!(cleared, "state cookie was not cleared");
+
+    let ids = user_ids(&app.pool, "al123").await;
+    assert_eq!(ids.len(), 1);
+    assert_eq!(linked_user(&app.pool, "sub-ada").await, Some(ids[0]));
+
+    // the state is single use
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn code_bound_to_another_verifier_is_refused(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let login = Login::start(&app).await;
+    let code = login.grant_with_challenge(
+        id_claims("sub-eve", "ev123", "07777777"),
+        &challenge_for("some other verifier"),
+    );
+
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert!(user_ids(&app.pool, "ev123").await.is_empty());
+}
+
+#[sqlx::test]
+async fn callback_needs_the_state_cookie_of_its_login(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let login = Login::start(&app).await;
+    let other = Login::start(&app).await;
+    let code = login.grant(id_claims("sub-bob", "bo123", "02222222"));
+
+    let response = login.callback(&app, &code, None).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let response = login.callback(&app, &code, Some(&other.cookie)).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert!(user_ids(&app.pool, "bo123").await.is_empty());
+
+    // refused callbacks leave the login to be finished by the browser that started it
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn login_links_the_account_with_the_same_shortcode_and_cid(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let existing = insert_user(&app.pool, "gh123", "03333333").await;
+
+    let login = Login::start(&app).await;
+    let code = login.grant(id_claims("sub-grace", "GH123", "03333333"));
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(user_ids(&app.pool, "gh123").await, vec![existing]);
+    assert_eq!(linked_user(&app.pool, "sub-grace").await, Some(existing));
+
+    // later logins go by the link, even if the username changes
+    let login = Login::start(&app).await;
+    let code = login.grant(id_claims("sub-grace", "grace.hopper", "03333333"));
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(user_ids(&app.pool, "grace.hopper").await.is_empty());
+}
+
+#[sqlx::test]
+async fn login_is_refused_for_a_shortcode_with_another_cid(pool: sqlx::PgPool) {
+    let app = app(pool);
+    insert_user(&app.pool, "mh123", "04444444").await;
+
+    let login = Login::start(&app).await;
+    let code = login.grant(id_claims("sub-mallory", "mh123", "05555555"));
+    let response = login.finish(&app, &code).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    assert_eq!(linked_user(&app.pool, "sub-mallory").await, None);
+}
+
+#[sqlx::test]
+async fn unusable_names_are_refused(pool: sqlx::PgPool) {
+    let app = app(pool);
+    for (given_name, family_name) in [
+        (json!("Robert'); --"), json!("Tables")),
+        (json!("A".repeat(31)), json!("Long")),
+        (json!(null), json!("Nameless")),
+    ] {
+        let mut claims = id_claims("sub-odd", "od123", "06666666");
+        claims["given_name"] = given_name;
+        claims["family_name"] = family_name;
+        let login = Login::start(&app).await;
+        let code = login.grant(claims);
+        let response = login.finish(&app, &code).await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    assert!(user_ids(&app.pool, "od123").await.is_empty());
+}
+
+#[sqlx::test]
+async fn simultaneous_first_logins_share_one_account(pool: sqlx::PgPool) {
+    let app = app(pool);
+    let first = Login::start(&app).await;
+    let second = Login::start(&app).await;
+    let first_code = first.grant(id_claims("sub-twin", "tw123", "08888888"));
+    let second_code = second.grant(id_claims("sub-twin", "tw123", "08888888"));
+
+    let (a, b) = tokio::join!(
+        first.finish(&app, &first_code),
+        second.finish(&app, &second_code)
+    );
+    assert_eq!(a.status, StatusCode::OK);
+    assert_eq!(b.status, StatusCode::OK);
+    let ids = user_ids(&app.pool, "tw123").await;
+    assert_eq!(ids.len(), 1);
+    assert_eq!(linked_user(&app.pool, "sub-twin").await, Some(ids[0]));
+}
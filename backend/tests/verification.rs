@@ -0,0 +1,157 @@
+mod common;
+
+use common::TestApp;
+use serde_json::json;
+
+fn registration(shortcode: &str, cid: &str) -> serde_json::Value {
+    json!({
+        "first_name": "Ada",
+        "surname": "Lovelace",
+        "shortcode": shortcode,
+        "cid": cid,
+        "password": "Password123",
+    })
+}
+
+/// The verification token is the only UUID in the email
+fn token_from(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .find(|line| uuid::Uuid::parse_str(line).is_ok())
+        .expect("no token in email")
+        .to_string()
+}
+
+async fn tier_of(pool: &sqlx::PgPool, shortcode: &str) -> Option<i16> {
+    sqlx::query_scalar!(
+        "SELECT tier FROM auth.users WHERE shortcode = $1",
+        shortcode
+    )
+    .fetch_optional(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test]
+async fn members_verify_before_their_account_is_created(pool: sqlx::PgPool) {
+    sqlx::query!("INSERT INTO records.members(cid, login) VALUES ('01234567', 'al123')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let app = TestApp::new(pool);
+
+    let mut body = registration("al123", "01234567");
+    body["created_at"] = json!("2000-01-01T00:00:00Z");
+    let response = app.post("/api/v1/users/register", body).await;
+    assert_eq!(response.status, 201);
+    assert_eq!(tier_of(&app.pool, "al123").await, None);
+
+    let created_at =
+        sqlx::query_scalar!("SELECT created_at FROM auth.pending_users WHERE shortcode = 'al123'")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert!(created_at.timestamp() > 946_684_800);
+
+    let sent = app.deliver_mail().await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to, "al123@ic.ac.uk");
+    let token = token_from(&sent[0].text);
+
+    // the token is not kept in the outbox once the email has gone
+    let stored = sqlx::query_scalar!("SELECT text_body FROM records.mail_outbox")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert!(!stored.contains(&token));
+
+    let response = app
+        .post(&format!("/api/v1/users/verify?token={token}"), json!(null))
+        .await;
+    assert_eq!(response.status, 201);
+    assert_eq!(tier_of(&app.pool, "al123").await, Some(1));
+
+    // tokens are single use
+    let response = app
+        .post(&format!("/api/v1/users/verify?token={token}"), json!(null))
+        .await;
+    assert_eq!(response.status, 422);
+}
+
+#[sqlx::test]
+async fn expired_tokens_are_refused(pool: sqlx::PgPool) {
+    let app = TestApp::new(pool);
+    app.post("/api/v1/users/register", registration("ex123", "07654321"))
+        .await;
+    let token = token_from(&app.deliver_mail().await[0].text);
+
+    sqlx::query!(
+        "UPDATE auth.pending_users SET token_sent_at = token_sent_at - interval '25 hours'"
+    )
+    .execute(&app.pool)
+    .await
+    .unwrap();
+
+    let response = app
+        .post(&format!("/api/v1/users/verify?token={token}"), json!(null))
+        .await;
+    assert_eq!(response.status, 422);
+    assert_eq!(tier_of(&app.pool, "ex123").await, None);
+}
+
+#[sqlx::test]
+async fn resend_waits_for_the_cooldown(pool: sqlx::PgPool) {
+    let app = TestApp::new(pool);
+    app.post("/api/v1/users/register", registration("rs123", "01111111"))
+        .await;
+
+    let resend = json!({ "shortcode": "rs123" });
+    let response = app
+        .post("/api/v1/users/verify/resend", resend.clone())
+        .await;
+    assert_eq!(response.status, 202);
+    let sent = app.deliver_mail().await;
+    assert_eq!(sent.len(), 1);
+    let first_token = token_from(&sent[0].text);
+
+    sqlx::query!(
+        "UPDATE auth.pending_users SET token_sent_at = token_sent_at - interval '10 minutes'"
+    )
+    .execute(&app.pool)
+    .await
+    .unwrap();
+    let response = app.post("/api/v1/users/verify/resend", resend).await;
+    assert_eq!(response.status, 202);
+    let sent = app.deliver_mail().await;
+    assert_eq!(sent.len(), 2);
+    let second_token = token_from(&sent[1].text);
+    assert_ne!(first_token, second_token);
+
+    let response = app
+        .post(
+            &format!("/api/v1/users/verify?token={first_token}"),
+            json!(null),
+        )
+        .await;
+    assert_eq!(response.status, 422);
+    let response = app
+        .post(
+            &format!("/api/v1/users/verify?token={second_token}"),
+            json!(null),
+        )
+        .await;
+    assert_eq!(response.status, 201);
+}
+
+#[sqlx::test]
+async fn resend_does_not_reveal_unknown_shortcodes(pool: sqlx::PgPool) {
+    let app = TestApp::new(pool);
+    let response = app
+        .post(
+            "/api/v1/users/verify/resend",
+            json!({ "shortcode": "nobody" }),
+        )
+        .await;
+    assert_eq!(response.status, 202);
+    assert!(app.deliver_mail().await.is_empty());
+}